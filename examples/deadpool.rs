@@ -1,12 +1,14 @@
 use ::deadpool::Runtime;
-use rusqlite::types::ValueRef;
 use skald::{
-    embedded_milli::{IndexSettings, Instance},
-    pool::deadpool::{self, Pool},
-    PrimaryKeyFn, StatementExt, TableIndexSettings,
+    embedded_milli::{IndexSettings, Instance, SearchOptions, UpdateMethod},
+    pool::{
+        deadpool::{self, Pool},
+        SyncConfig,
+    },
+    DocumentKey, StatementExt, TableMapping,
 };
 use slite::Migrator;
-use std::{fs::File, io::Read, thread, time::Duration};
+use std::{fs::File, io::Read};
 
 #[tokio::main]
 async fn main() {
@@ -54,13 +56,13 @@ async fn main() {
         .prepare("select artist_id, artist_name, extra from artist")
         .unwrap();
     index
-        .set_documents(&mut wtxn, statement.query_to_json([]))
+        .set_documents(&mut wtxn, statement.query_to_json([]).unwrap())
         .unwrap();
-    wtxn.commit().unwrap();
+    index.commit(wtxn).unwrap();
 
     let rtxn = index.read();
     let res = index
-        .search_documents(&rtxn, |search| {
+        .search_documents(&rtxn, SearchOptions::default(), |search| {
             search.query("test2");
         })
         .unwrap();
@@ -70,32 +72,29 @@ async fn main() {
         &deadpool_sqlite::Config::new(path),
         Runtime::Tokio1,
         instance,
+        SyncConfig::default(),
     )
-    .with_table(
+    .with_mapping(
         "main".to_owned(),
-        "artist".to_owned(),
-        vec![TableIndexSettings {
+        TableMapping {
+            table: "artist".to_owned(),
             index_name: "artist".to_owned(),
-            update_query: "select artist_id, artist_name, extra from artist where rowid = ?"
-                .to_owned(),
-            primary_key_fn: PrimaryKeyFn::new(|accessor| {
-                if let ValueRef::Integer(val) = accessor.get_old_column_value(0) {
-                    val.to_string()
-                } else {
-                    unreachable!()
-                }
-            }),
-        }],
-    );
+            document_key: DocumentKey::column("artist_id"),
+            columns: vec!["artist_name".to_owned(), "extra".to_owned()],
+            update_method: UpdateMethod::Replace,
+            filter: None,
+        },
+    )
+    .unwrap();
     let pool = Pool::builder(manager).build().unwrap();
 
     let conn = pool.get().await.unwrap();
     conn.lock().unwrap().execute("insert into artist(artist_name, created_date, extra) values('test', DATE('now'), '{\"yo\":[true,2]}')", []).unwrap();
-    thread::sleep(Duration::from_millis(100));
+    pool.manager().flush().await;
     let rtxn = index.read();
 
     let res = index
-        .search_documents(&rtxn, |search| {
+        .search_documents(&rtxn, SearchOptions::default(), |search| {
             search.query("test");
         })
         .unwrap();
@@ -104,10 +103,10 @@ async fn main() {
         .unwrap()
         .execute("delete from artist", [])
         .unwrap();
-    thread::sleep(Duration::from_millis(100));
+    pool.manager().flush().await;
     let rtxn = index.read();
     let res = index
-        .search_documents(&rtxn, |search| {
+        .search_documents(&rtxn, SearchOptions::default(), |search| {
             search.query("test");
         })
         .unwrap();
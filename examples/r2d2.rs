@@ -1,11 +1,14 @@
-use std::{fs::File, io::Read, thread, time::Duration};
+use std::{fs::File, io::Read};
 
 use r2d2::{ManageConnection, Pool};
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{types::ValueRef, OpenFlags};
 use skald::{
-    embedded_milli::{IndexSettings, Instance},
-    pool::r2d2::SkaldConnectionManager,
+    embedded_milli::{
+        FilterExpr, IndexSettings, Instance, SearchOptions, SearchQuery, SortCriterion,
+        UpdateMethod,
+    },
+    pool::{r2d2::SkaldConnectionManager, SyncConfig},
     PrimaryKeyFn, StatementExt, TableIndexSettings,
 };
 use slite::Migrator;
@@ -56,51 +59,63 @@ fn main() {
         .prepare("select artist_id, artist_name, extra from artist")
         .unwrap();
     index
-        .set_documents(&mut wtxn, statement.query_to_json([]))
+        .set_documents(&mut wtxn, statement.query_to_json([]).unwrap())
         .unwrap();
-    wtxn.commit().unwrap();
+    index.commit(wtxn).unwrap();
 
     let rtxn = index.read();
     let res = index
-        .search_documents(&rtxn, |search| {
+        .search_documents(&rtxn, SearchOptions::default(), |search| {
             search.query("test2");
         })
         .unwrap();
     println!("RES0 {res:?}");
 
-    let manager = SkaldConnectionManager::new(manager, instance).with_table(
+    let manager = SkaldConnectionManager::new(manager, instance, SyncConfig::default()).with_table(
         "main".to_owned(),
         "artist".to_owned(),
         vec![TableIndexSettings {
             index_name: "artist".to_owned(),
             update_query: "select artist_id, artist_name, extra from artist where rowid = ?"
                 .to_owned(),
+            batch_update_query: Some(
+                "select artist_id, artist_name, extra from artist \
+                where rowid in (select value from json_each(?))"
+                    .to_owned(),
+            ),
+            rebuild_query: "select artist_id, artist_name, extra from artist".to_owned(),
+            update_method: UpdateMethod::Replace,
+            filter: None,
             primary_key_fn: PrimaryKeyFn::new(|accessor| {
-                if let ValueRef::Integer(val) = accessor.get_old_column_value(0) {
-                    val.to_string()
+                if let ValueRef::Integer(val) = accessor.column_value(0) {
+                    Some(val.to_string())
                 } else {
-                    unreachable!()
+                    None
                 }
             }),
         }],
     );
+    let handler = manager.handler();
     let pool = Pool::new(manager).unwrap();
     let conn = pool.get().unwrap();
     conn.execute("insert into artist(artist_name, created_date, extra) values('test', DATE('now'), '{\"yo\":[true,2]}')", []).unwrap();
-    thread::sleep(Duration::from_millis(100));
+    handler.flush();
     let rtxn = index.read();
 
     let res = index
-        .search_documents(&rtxn, |search| {
-            search.query("test");
-        })
+        .search(
+            &rtxn,
+            &SearchQuery::new("test")
+                .with_filter(FilterExpr::eq("artist_name", "test"))
+                .with_sort(SortCriterion::Asc("artist_id".to_owned())),
+        )
         .unwrap();
     println!("RES1 {res:?}");
     conn.execute("delete from artist", []).unwrap();
-    thread::sleep(Duration::from_millis(100));
+    handler.flush();
     let rtxn = index.read();
     let res = index
-        .search_documents(&rtxn, |search| {
+        .search_documents(&rtxn, SearchOptions::default(), |search| {
             search.query("test");
         })
         .unwrap();
@@ -1,11 +1,14 @@
 use skald::{
-    embedded_milli::{IndexSettings, Instance},
-    pool::sqlx::{IntoConnection, QueryExt, SkaldHooks},
+    embedded_milli::{IndexSettings, Instance, SearchOptions, UpdateMethod},
+    pool::{
+        sqlx::{IntoConnection, QueryExt, SkaldHooks},
+        SyncConfig,
+    },
     PrimaryKeyFn, TableIndexSettings,
 };
 use slite::Migrator;
 use sqlx::{sqlite::SqlitePoolOptions, ValueRef};
-use std::{fs::File, io::Read, thread, time::Duration};
+use std::{fs::File, io::Read};
 
 #[tokio::main]
 async fn main() {
@@ -54,32 +57,38 @@ async fn main() {
         .await
         .unwrap();
     index.set_documents(&mut wtxn, res.query_to_json()).unwrap();
-    wtxn.commit().unwrap();
+    index.commit(wtxn).unwrap();
 
     let rtxn = index.read();
     let res = index
-        .search_documents(&rtxn, |search| {
+        .search_documents(&rtxn, SearchOptions::default(), |search| {
             search.query("test2");
         })
         .unwrap();
     println!("RES0 {res:?}");
 
-    let hooks = SkaldHooks::new(&pool, instance).await.with_table(
-        "main".to_owned(),
-        "artist".to_owned(),
-        vec![TableIndexSettings {
-            index_name: "artist".to_owned(),
-            update_query: "select artist_id, artist_name, extra from artist where rowid = ?"
-                .to_owned(),
-            primary_key_fn: PrimaryKeyFn::new(|accessor| {
-                if let rusqlite::types::ValueRef::Integer(val) = accessor.get_old_column_value(0) {
-                    val.to_string()
-                } else {
-                    unreachable!()
-                }
-            }),
-        }],
-    );
+    let hooks = SkaldHooks::new(&pool, instance, SyncConfig::default())
+        .await
+        .with_table(
+            "main".to_owned(),
+            "artist".to_owned(),
+            vec![TableIndexSettings {
+                index_name: "artist".to_owned(),
+                update_query: "select artist_id, artist_name, extra from artist where rowid = ?"
+                    .to_owned(),
+                batch_update_query: None,
+                rebuild_query: "select artist_id, artist_name, extra from artist".to_owned(),
+                update_method: UpdateMethod::Replace,
+                filter: None,
+                primary_key_fn: PrimaryKeyFn::new(|accessor| {
+                    if let rusqlite::types::ValueRef::Integer(val) = accessor.column_value(0) {
+                        Some(val.to_string())
+                    } else {
+                        None
+                    }
+                }),
+            }],
+        );
     let pool = SqlitePoolOptions::default()
         .after_connect(hooks.build())
         .connect(path)
@@ -88,11 +97,11 @@ async fn main() {
 
     sqlx::query("insert into artist(artist_name, created_date, extra) values('test', DATE('now'), '{\"yo\":[true,2]}')").execute(&pool).await.unwrap();
 
-    thread::sleep(Duration::from_millis(100));
+    hooks.flush().await;
     let rtxn = index.read();
 
     let res = index
-        .search_documents(&rtxn, |search| {
+        .search_documents(&rtxn, SearchOptions::default(), |search| {
             search.query("test");
         })
         .unwrap();
@@ -101,10 +110,10 @@ async fn main() {
         .execute(&pool)
         .await
         .unwrap();
-    thread::sleep(Duration::from_millis(100));
+    hooks.flush().await;
     let rtxn = index.read();
     let res = index
-        .search_documents(&rtxn, |search| {
+        .search_documents(&rtxn, SearchOptions::default(), |search| {
             search.query("test");
         })
         .unwrap();
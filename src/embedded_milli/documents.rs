@@ -0,0 +1,82 @@
+use anyhow::Result;
+use milli::{heed, FieldsIdsMap, Filter, Index};
+
+use super::{Document, EmbeddedMilli, FilterExpr};
+
+/// Selects which documents are returned by [`EmbeddedMilli::documents`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DocumentsQuery {
+    /// Fields to include in each document. Defaults to every field.
+    pub fields: Option<Vec<String>>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+    pub filter: Option<FilterExpr>,
+}
+
+/// Lazily reads documents from the index, in internal document id order.
+pub struct DocumentsIter<'t> {
+    index: &'t Index,
+    rtxn: &'t heed::RoTxn<'t>,
+    fields_ids_map: FieldsIdsMap,
+    fields: Option<Vec<String>>,
+    document_ids: Box<dyn Iterator<Item = u32> + 't>,
+}
+
+impl<'t> Iterator for DocumentsIter<'t> {
+    type Item = Result<Document>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let document_id = self.document_ids.next()?;
+        Some(self.read_document(document_id))
+    }
+}
+
+impl<'t> DocumentsIter<'t> {
+    fn read_document(&self, document_id: u32) -> Result<Document> {
+        let documents = self.index.documents(self.rtxn, [document_id])?;
+        let Some((_id, document)) = documents.first() else {
+            return Err(anyhow::anyhow!("Missing document"));
+        };
+        let mut document = milli::all_obkv_to_json(*document, &self.fields_ids_map)?;
+        if let Some(fields) = &self.fields {
+            if !fields.iter().any(|field| field == "*") {
+                document.retain(|key, _| fields.contains(key));
+            }
+        }
+        Ok(document)
+    }
+}
+
+impl EmbeddedMilli {
+    /// Returns an iterator over the documents that match the query. Documents are only read
+    /// from the index as the iterator advances, so large indexes can be exported without
+    /// loading everything into memory.
+    pub fn documents<'t>(
+        &'t self,
+        rtxn: &'t heed::RoTxn<'t>,
+        query: &DocumentsQuery,
+    ) -> Result<DocumentsIter<'t>> {
+        let candidates = match &query.filter {
+            Some(filter) => {
+                filter.validate(&self.index.filterable_fields(rtxn)?)?;
+                match Filter::from_str(&filter.to_string())? {
+                    Some(filter) => filter.evaluate(rtxn, &self.index)?,
+                    None => self.index.documents_ids(rtxn)?,
+                }
+            }
+            None => self.index.documents_ids(rtxn)?,
+        };
+        let document_ids = candidates
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX));
+
+        Ok(DocumentsIter {
+            index: &self.index,
+            rtxn,
+            fields_ids_map: self.index.fields_ids_map(rtxn)?,
+            fields: query.fields.clone(),
+            document_ids: Box::new(document_ids),
+        })
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use milli::{heed, FacetDistribution, Filter, Search, SearchForFacetValues};
+
+use super::{query::check_filterable, EmbeddedMilli, FilterExpr};
+
+/// A facet value along with the number of documents that contain it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FacetHit {
+    pub value: String,
+    pub count: u64,
+}
+
+impl EmbeddedMilli {
+    /// Counts the documents for each value of the given facets. The fields need to be in
+    /// [`IndexSettings::filterable_fields`](super::IndexSettings::filterable_fields).
+    ///
+    /// If a filter is given, only the documents that match it are counted. At most
+    /// [`IndexSettings::max_values_per_facet`](super::IndexSettings::max_values_per_facet)
+    /// values are returned for each facet.
+    pub fn facet_distribution(
+        &self,
+        rtxn: &heed::RoTxn,
+        fields: &[String],
+        filter: Option<&FilterExpr>,
+    ) -> Result<BTreeMap<String, BTreeMap<String, u64>>> {
+        let filterable_fields = self.index.filterable_fields(rtxn)?;
+        for field in fields {
+            check_filterable(field, &filterable_fields)?;
+        }
+        if let Some(filter) = filter {
+            filter.validate(&filterable_fields)?;
+        }
+
+        let mut distribution = FacetDistribution::new(rtxn, &self.index);
+        distribution.facets(fields);
+        if let Some(max_values_per_facet) = self.index.max_values_per_facet(rtxn)? {
+            distribution.max_values_per_facet(max_values_per_facet);
+        }
+        let filter_expr = filter.map(ToString::to_string);
+        if let Some(filter) = filter_expr
+            .as_deref()
+            .map(Filter::from_str)
+            .transpose()?
+            .flatten()
+        {
+            distribution.candidates(filter.evaluate(rtxn, &self.index)?);
+        }
+
+        Ok(distribution
+            .execute()?
+            .into_iter()
+            .map(|(field, values)| (field, values.into_iter().collect()))
+            .collect())
+    }
+
+    /// Finds the values of a facet that match the query, e.g. every genre starting with "ro".
+    /// The query is matched the same way as a search, so prefixes and typos are allowed.
+    /// The field needs to be filterable.
+    ///
+    /// If a filter is given, only values from the documents that match it are returned.
+    pub fn search_facet_values(
+        &self,
+        rtxn: &heed::RoTxn,
+        field: &str,
+        query: &str,
+        filter: Option<&FilterExpr>,
+    ) -> Result<Vec<FacetHit>> {
+        let filterable_fields = self.index.filterable_fields(rtxn)?;
+        check_filterable(field, &filterable_fields)?;
+        if let Some(filter) = filter {
+            filter.validate(&filterable_fields)?;
+        }
+
+        let mut search = Search::new(rtxn, &self.index);
+        let filter_expr = filter.map(ToString::to_string);
+        if let Some(filter) = filter_expr
+            .as_deref()
+            .map(Filter::from_str)
+            .transpose()?
+            .flatten()
+        {
+            search.filter(filter);
+        }
+
+        let mut facet_search = SearchForFacetValues::new(field.to_owned(), search);
+        facet_search.query(query);
+
+        Ok(facet_search
+            .execute()?
+            .into_iter()
+            .map(|hit| FacetHit {
+                value: hit.value,
+                count: hit.count,
+            })
+            .collect())
+    }
+}
@@ -3,17 +3,35 @@ use std::{
     fs,
     io::Cursor,
     path::{Path, PathBuf},
-    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use anyhow::{anyhow, Result};
 use derivative::Derivative;
 use milli::{
     documents::{DocumentsBatchBuilder, DocumentsBatchReader},
-    heed, update, Criterion, Index, Search, SearchResult,
+    heed, update, Criterion, Index,
 };
 use once_cell::sync::Lazy;
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+mod documents;
+mod facet;
+mod query;
+mod search;
+mod settings;
+mod typed;
+
+pub use documents::*;
+pub use facet::*;
+pub use query::*;
+pub use search::*;
+pub use settings::*;
+pub use typed::*;
 
 // The following constants are for the map size used in heed/LMDB.
 // We assume any OS we run on will have a page size less than 16 MiB (2^24)
@@ -32,16 +50,25 @@ pub type Document = serde_json::Map<String, serde_json::Value>;
 #[derive(Clone)]
 pub struct EmbeddedMilli {
     index: Index,
+    // Copy of the index settings kept outside of LMDB, see `Instance::recreate_index`
+    settings_path: PathBuf,
+    // Set when the settings were updated in a transaction that hasn't been committed through
+    // `commit` yet
+    settings_changed: Arc<AtomicBool>,
 }
 
 /// Represents the synonyms of a given word
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Synonyms {
     pub word: String,
     pub synonyms: Vec<String>,
 }
 
-#[derive(Clone, Eq, PartialEq, Derivative)]
+/// Settings of an index.
+///
+/// Proximity precision and separator tokens aren't available because milli v1.3 doesn't
+/// support them yet.
+#[derive(Clone, Eq, PartialEq, Derivative, Serialize, Deserialize)]
 #[derivative(Debug, Default)]
 pub struct IndexSettings {
     pub primary_key: Option<String>,
@@ -57,9 +84,38 @@ pub struct IndexSettings {
     pub min_word_size_for_two_typos: Option<u8>,
     pub disallow_typos_on_words: Vec<String>,
     pub disallow_typos_on_fields: Vec<String>,
+    /// Fields returned in documents. `None` returns every field.
+    pub displayed_fields: Option<Vec<String>>,
+    /// Only one document is returned for each value of this field.
+    pub distinct_attribute: Option<String>,
+    /// Maximum number of values returned for each facet. `None` uses milli's default.
+    pub max_values_per_facet: Option<usize>,
+    /// Maximum number of hits a search can reach, including the offset. `None` doesn't limit
+    /// the hits.
+    pub pagination_max_total_hits: Option<usize>,
+}
+
+/// How documents are written when one with the same primary key is already in the index.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UpdateMethod {
+    /// The existing document is replaced entirely.
+    #[default]
+    Replace,
+    /// The new fields are merged into the existing document.
+    Update,
+}
+
+impl From<UpdateMethod> for update::IndexDocumentsMethod {
+    fn from(method: UpdateMethod) -> Self {
+        match method {
+            UpdateMethod::Replace => update::IndexDocumentsMethod::ReplaceDocuments,
+            UpdateMethod::Update => update::IndexDocumentsMethod::UpdateDocuments,
+        }
+    }
 }
 
 const CURRENT_MILLI_VERSION: u32 = 1;
+const SETTINGS_FILE_NAME: &str = "settings.json";
 
 static INDEXES: Lazy<RwLock<HashMap<PathBuf, EmbeddedMilli>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
@@ -67,30 +123,58 @@ static INDEXES: Lazy<RwLock<HashMap<PathBuf, EmbeddedMilli>>> =
 #[derive(Clone)]
 pub struct Instance {
     instance_dir: PathBuf,
+    // Set when the indexes are kept in sync with a database that they can be rebuilt from
+    pub(crate) recreate_incompatible_indexes: bool,
 }
 
 impl Instance {
     pub fn new(instance_dir: impl Into<PathBuf>) -> Self {
         Self {
             instance_dir: instance_dir.into(),
+            recreate_incompatible_indexes: false,
         }
     }
 
-    pub fn get_milli_version(&self) -> u32 {
-        let contents =
-            fs::read_to_string(Path::new(&self.instance_dir).join("milli_version")).unwrap();
-        contents.parse().unwrap()
+    /// Returns the milli version marker of the index, or `None` if the index was created
+    /// before markers were written.
+    pub fn get_milli_version(&self, name: impl AsRef<str>) -> Result<Option<u32>> {
+        let path = self.milli_version_path(name.as_ref());
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path)?;
+        Ok(Some(contents.trim().parse()?))
     }
 
-    pub fn needs_rebuilding(&self) -> bool {
-        self.get_milli_version() < CURRENT_MILLI_VERSION
+    /// Returns true if the index was written by an older milli version (or has no version
+    /// marker) and needs to be rebuilt from its source.
+    pub fn needs_rebuilding(&self, name: impl AsRef<str>) -> Result<bool> {
+        Ok(self
+            .get_milli_version(name)?
+            .map_or(true, |version| version < CURRENT_MILLI_VERSION))
+    }
+
+    /// Marks the index as written by the current milli version. Call this after the index has
+    /// been rebuilt.
+    pub fn mark_up_to_date(&self, name: impl AsRef<str>) -> Result<()> {
+        fs::write(
+            self.milli_version_path(name.as_ref()),
+            CURRENT_MILLI_VERSION.to_string(),
+        )?;
+        Ok(())
+    }
+
+    fn milli_version_path(&self, name: &str) -> PathBuf {
+        self.instance_dir.join(name).join("milli_version")
     }
 
     pub fn get_index(&self, name: impl AsRef<str>) -> Result<EmbeddedMilli> {
-        let dir = self.instance_dir.join(name.as_ref());
+        let name = name.as_ref();
+        let dir = self.instance_dir.join(name);
         if let Some(index) = INDEXES.read().get(&dir) {
             return Ok(index.clone());
         }
+        let is_new = !dir.exists();
         std::fs::create_dir_all(&dir)?;
 
         // We need this exponential backoff retry crap due to iOS' limited address space,
@@ -118,29 +202,154 @@ impl Instance {
             };
         }
 
-        let mut options = heed::EnvOpenOptions::new();
-        options.map_size(map_size);
+        let open_index = || {
+            let mut options = heed::EnvOpenOptions::new();
+            options.map_size(map_size);
+            Index::new(options, &dir)
+        };
+
+        let res = match open_index() {
+            Ok(index) => {
+                let index = EmbeddedMilli::new(index, dir.join(SETTINGS_FILE_NAME));
+                // Settings can also be changed without going through `commit`, so refresh the
+                // copy while the index can still be read
+                index.write_settings_backup()?;
+                index
+            }
+            Err(e)
+                if !is_new && self.recreate_incompatible_indexes && is_incompatible_version(&e) =>
+            {
+                self.recreate_index(name, &dir, e, open_index)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if is_new {
+            self.mark_up_to_date(name)?;
+        }
 
-        let index = Index::new(options, &dir).map_err(anyhow::Error::from)?;
-        let res = EmbeddedMilli { index };
         INDEXES.write().insert(dir, res.clone());
         Ok(res)
     }
+
+    /// Replaces an index written by an incompatible milli version with an empty one that has
+    /// the same settings, so it can be rebuilt from its source.
+    ///
+    /// The settings are restored from the copy written whenever they're updated. Without that
+    /// copy, or if the version marker says the index is current, the open error is returned
+    /// instead since wiping the index would lose data that can't be restored.
+    fn recreate_index(
+        &self,
+        name: &str,
+        dir: &Path,
+        error: milli::Error,
+        open_index: impl Fn() -> milli::Result<Index>,
+    ) -> Result<EmbeddedMilli> {
+        let settings_path = dir.join(SETTINGS_FILE_NAME);
+        if !self.needs_rebuilding(name)? || !settings_path.exists() {
+            return Err(error.into());
+        }
+        let settings: IndexSettings = serde_json::from_str(&fs::read_to_string(&settings_path)?)?;
+
+        // The version marker is removed along with the index, so the rebuild still happens
+        fs::remove_dir_all(dir)?;
+        fs::create_dir_all(dir)?;
+        let index = EmbeddedMilli::new(open_index()?, settings_path);
+        let mut wtxn = index.try_write()?;
+        index.set_settings(&mut wtxn, settings)?;
+        index.commit(wtxn)?;
+
+        Ok(index)
+    }
+}
+
+/// Returns true if the error means the index was written in a format this milli version
+/// doesn't understand, as opposed to a failure to open it, such as a permission error or
+/// running out of address space.
+fn is_incompatible_version(error: &milli::Error) -> bool {
+    matches!(
+        error,
+        milli::Error::InternalError(
+            milli::InternalError::DatabaseMissingEntry { .. }
+                | milli::InternalError::Serialization(_)
+                | milli::InternalError::Store(
+                    heed::MdbError::VersionMismatch
+                        | heed::MdbError::Incompatible
+                        | heed::MdbError::Invalid
+                )
+        )
+    )
 }
 
 impl EmbeddedMilli {
+    fn new(index: Index, settings_path: PathBuf) -> Self {
+        Self {
+            index,
+            settings_path,
+            settings_changed: Default::default(),
+        }
+    }
+
+    /// Commits the transaction. If the settings were updated, the copy of them that's used
+    /// to recreate the index after a milli upgrade is refreshed once the commit succeeded, so
+    /// prefer this over committing the transaction directly.
+    pub fn commit(&self, wtxn: heed::RwTxn) -> Result<()> {
+        wtxn.commit()?;
+        if self.settings_changed.swap(false, Ordering::SeqCst) {
+            self.write_settings_backup()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the committed settings next to the index. The file is replaced atomically so a
+    /// crash can't leave a partial copy behind.
+    fn write_settings_backup(&self) -> Result<()> {
+        let rtxn = self.index.read_txn()?;
+        let settings = serde_json::to_string(&self.get_settings(&rtxn)?)?;
+        let temp_path = self.settings_path.with_extension("json.tmp");
+        fs::write(&temp_path, settings)?;
+        fs::rename(temp_path, &self.settings_path)?;
+        Ok(())
+    }
+
     pub fn write(&self) -> heed::RwTxn<'_, '_> {
-        self.index.write_txn().unwrap()
+        self.try_write().unwrap()
+    }
+
+    /// Same as [`write`](Self::write), but returns an error if the transaction can't be
+    /// started.
+    pub fn try_write(&self) -> Result<heed::RwTxn<'_, '_>> {
+        Ok(self.index.write_txn()?)
     }
 
     pub fn read(&self) -> heed::RoTxn<'_> {
         self.index.read_txn().unwrap()
     }
 
+    /// Adds the documents to the index, replacing any existing documents with the same
+    /// primary key.
     pub fn add_documents<'t>(
         &'t self,
         wtxn: &mut heed::RwTxn<'t, '_>,
         documents: Vec<Document>,
+    ) -> Result<()> {
+        self.index_documents(wtxn, documents, UpdateMethod::Replace)
+    }
+
+    /// Adds the documents to the index, merging their fields into any existing documents with
+    /// the same primary key. Fields that aren't in the new document keep their current value.
+    pub fn update_documents<'t>(
+        &'t self,
+        wtxn: &mut heed::RwTxn<'t, '_>,
+        documents: Vec<Document>,
+    ) -> Result<()> {
+        self.index_documents(wtxn, documents, UpdateMethod::Update)
+    }
+
+    pub fn index_documents<'t>(
+        &'t self,
+        wtxn: &mut heed::RwTxn<'t, '_>,
+        documents: Vec<Document>,
+        update_method: UpdateMethod,
     ) -> Result<()> {
         // Create a batch builder to convert json_documents into milli's format
         let mut builder = DocumentsBatchBuilder::new(Vec::new());
@@ -154,7 +363,10 @@ impl EmbeddedMilli {
 
         // Create the configs needed for the batch document addition
         let indexer_config = update::IndexerConfig::default();
-        let indexing_config = update::IndexDocumentsConfig::default();
+        let indexing_config = update::IndexDocumentsConfig {
+            update_method: update_method.into(),
+            ..Default::default()
+        };
 
         // Make an index write transaction with a batch step to index the new documents
 
@@ -252,6 +464,8 @@ impl EmbeddedMilli {
             .map_err(anyhow::Error::from)
     }
 
+    /// Loads every document into memory. Prefer [`documents`](Self::documents) for large
+    /// indexes.
     pub fn get_all_documents(&self) -> Result<Vec<Document>> {
         let rtxn = self.index.read_txn()?;
         let fields_ids_map = self.index.fields_ids_map(&rtxn)?;
@@ -262,26 +476,8 @@ impl EmbeddedMilli {
             .collect()
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn search_documents(
-        &self,
-        rtxn: &heed::RoTxn,
-        build_search: impl FnOnce(&mut Search),
-    ) -> Result<Vec<Document>> {
-        // Create the search
-
-        let mut search = Search::new(rtxn, &self.index);
-        build_search(&mut search);
-
-        // Get the documents based on the search results
-        let SearchResult { documents_ids, .. } = search.execute()?;
-        let fields_ids_map = self.index.fields_ids_map(rtxn)?;
-        self.index
-            .documents(rtxn, documents_ids)?
-            .iter()
-            .map(|(_id, doc)| milli::all_obkv_to_json(*doc, &fields_ids_map))
-            .map(|r| r.map_err(anyhow::Error::from))
-            .collect()
+    pub fn primary_key(&self, rtxn: &heed::RoTxn) -> Result<Option<String>> {
+        Ok(self.index.primary_key(rtxn)?.map(Into::into))
     }
 
     pub fn number_of_documents(&self, rtxn: &heed::RoTxn) -> Result<u64> {
@@ -349,69 +545,254 @@ impl EmbeddedMilli {
                 .into_iter()
                 .map(String::from)
                 .collect(),
+            displayed_fields: self
+                .index
+                .displayed_fields(rtxn)?
+                .map(|fields| fields.into_iter().map(String::from).collect()),
+            distinct_attribute: self.index.distinct_field(rtxn)?.map(String::from),
+            max_values_per_facet: self.index.max_values_per_facet(rtxn)?,
+            pagination_max_total_hits: self.index.pagination_max_total_hits(rtxn)?,
         })
     }
 
+    /// Replaces every setting of the index. Use [`update_settings`](Self::update_settings)
+    /// to only change some of them.
     pub fn set_settings<'t>(
         &'t self,
         wtxn: &mut heed::RwTxn<'t, '_>,
         settings: IndexSettings,
     ) -> Result<()> {
-        // Destructure the settings into the corresponding fields
-        let IndexSettings {
-            primary_key,
-            searchable_fields,
-            filterable_fields,
-            sortable_fields,
-            ranking_rules,
-            stop_words,
-            synonyms,
-            typos_enabled,
-            min_word_size_for_one_typo,
-            min_word_size_for_two_typos,
-            disallow_typos_on_words,
-            disallow_typos_on_fields,
-        } = settings;
-
-        // Set up the settings update
-
-        let indexer_config = update::IndexerConfig::default();
-        let mut builder = update::Settings::new(wtxn, &self.index, &indexer_config);
+        self.update_settings(wtxn, settings.into())
+    }
+}
 
-        // Copy over the given settings
-        match primary_key {
-            Some(pkey) => builder.set_primary_key(pkey),
-            None => builder.reset_primary_key(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_settings() -> IndexSettings {
+        IndexSettings {
+            displayed_fields: Some(vec!["title".to_owned(), "artist".to_owned()]),
+            distinct_attribute: Some("album".to_owned()),
+            max_values_per_facet: Some(25),
+            pagination_max_total_hits: Some(500),
+            ..Default::default()
         }
-        match searchable_fields {
-            Some(fields) => builder.set_searchable_fields(fields),
-            None => builder.reset_searchable_fields(),
-        }
-        builder.set_filterable_fields(filterable_fields.into_iter().collect());
-        builder.set_sortable_fields(sortable_fields.into_iter().collect());
-        builder.set_criteria(
-            ranking_rules
-                .iter()
-                .map(String::as_str)
-                .map(milli::Criterion::from_str)
-                .map(|r| r.map_err(anyhow::Error::from))
-                .collect::<Result<_>>()?,
+    }
+
+    fn round_trip(index: &EmbeddedMilli, settings: &IndexSettings) {
+        let mut wtxn = index.write();
+        index.set_settings(&mut wtxn, settings.clone()).unwrap();
+        index.commit(wtxn).unwrap();
+
+        let rtxn = index.read();
+        let stored = index.get_settings(&rtxn).unwrap();
+        assert_eq!(settings.displayed_fields, stored.displayed_fields);
+        assert_eq!(settings.distinct_attribute, stored.distinct_attribute);
+        assert_eq!(settings.max_values_per_facet, stored.max_values_per_facet);
+        assert_eq!(
+            settings.pagination_max_total_hits,
+            stored.pagination_max_total_hits
         );
-        builder.set_stop_words(stop_words.into_iter().collect());
-        builder.set_synonyms(synonyms.into_iter().map(|s| (s.word, s.synonyms)).collect());
-        builder.set_autorize_typos(typos_enabled);
-        if let Some(val) = min_word_size_for_one_typo {
-            builder.set_min_word_len_one_typo(val);
-        }
-        if let Some(val) = min_word_size_for_two_typos {
-            builder.set_min_word_len_two_typos(val);
-        }
-        builder.set_exact_words(disallow_typos_on_words.into_iter().collect());
-        builder.set_exact_attributes(disallow_typos_on_fields.into_iter().collect());
+    }
 
-        // Execute the settings update
-        builder.execute(|_| {}, || false)?;
+    #[test]
+    fn set_settings_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = Instance::new(dir.path()).get_index("test").unwrap();
+        round_trip(&index, &new_settings());
+    }
 
-        Ok(())
+    #[test]
+    fn reset_settings_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = Instance::new(dir.path()).get_index("test").unwrap();
+        round_trip(&index, &new_settings());
+        round_trip(
+            &index,
+            &IndexSettings {
+                displayed_fields: None,
+                distinct_attribute: None,
+                max_values_per_facet: None,
+                pagination_max_total_hits: None,
+                ..new_settings()
+            },
+        );
+    }
+
+    #[test]
+    fn settings_backup_follows_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = Instance::new(dir.path()).get_index("test").unwrap();
+        let backup = || -> IndexSettings {
+            serde_json::from_str(&fs::read_to_string(&index.settings_path).unwrap()).unwrap()
+        };
+        round_trip(&index, &new_settings());
+        assert_eq!(Some(25), backup().max_values_per_facet);
+
+        let mut wtxn = index.write();
+        index
+            .set_settings(
+                &mut wtxn,
+                IndexSettings {
+                    max_values_per_facet: Some(10),
+                    ..new_settings()
+                },
+            )
+            .unwrap();
+        drop(wtxn);
+        assert_eq!(Some(25), backup().max_values_per_facet);
+        assert!(!index.settings_path.with_extension("json.tmp").exists());
+    }
+
+    fn index_with_documents(dir: &Path, settings: IndexSettings) -> EmbeddedMilli {
+        let index = Instance::new(dir).get_index("test").unwrap();
+        let mut wtxn = index.write();
+        index.set_settings(&mut wtxn, settings).unwrap();
+        let documents = ["rock", "jazz", "blues", "folk", "soul"]
+            .into_iter()
+            .enumerate()
+            .map(|(id, genre)| {
+                serde_json::json!({ "id": id, "genre": genre, "secret": "hidden" })
+                    .as_object()
+                    .unwrap()
+                    .clone()
+            })
+            .collect();
+        index.add_documents(&mut wtxn, documents).unwrap();
+        index.commit(wtxn).unwrap();
+        index
+    }
+
+    #[test]
+    fn facet_distribution_uses_max_values_per_facet() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index_with_documents(
+            dir.path(),
+            IndexSettings {
+                primary_key: Some("id".to_owned()),
+                filterable_fields: vec!["genre".to_owned()],
+                max_values_per_facet: Some(2),
+                ..Default::default()
+            },
+        );
+
+        let rtxn = index.read();
+        let distribution = index
+            .facet_distribution(&rtxn, &["genre".to_owned()], None)
+            .unwrap();
+        assert_eq!(2, distribution["genre"].len());
+    }
+
+    #[test]
+    fn search_stops_at_pagination_max_total_hits() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index_with_documents(
+            dir.path(),
+            IndexSettings {
+                primary_key: Some("id".to_owned()),
+                pagination_max_total_hits: Some(3),
+                ..Default::default()
+            },
+        );
+
+        let rtxn = index.read();
+        let search = |offset, limit| {
+            index
+                .search_documents(
+                    &rtxn,
+                    SearchOptions {
+                        pagination: Pagination::OffsetLimit { offset, limit },
+                        ..Default::default()
+                    },
+                    |_| {},
+                )
+                .unwrap()
+        };
+        let response = search(0, 20);
+        assert_eq!(3, response.hits.len());
+        assert_eq!(
+            HitsInfo::OffsetLimit {
+                offset: 0,
+                limit: 20,
+                estimated_total_hits: 3,
+            },
+            response.hits_info
+        );
+        assert_eq!(1, search(2, 20).hits.len());
+        assert!(search(4, 20).hits.is_empty());
+    }
+
+    #[test]
+    fn search_pages_start_at_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index_with_documents(
+            dir.path(),
+            IndexSettings {
+                primary_key: Some("id".to_owned()),
+                ..Default::default()
+            },
+        );
+
+        let rtxn = index.read();
+        let search = |page| {
+            index
+                .search_documents(
+                    &rtxn,
+                    SearchOptions {
+                        pagination: Pagination::Page {
+                            page,
+                            hits_per_page: 2,
+                        },
+                        ..Default::default()
+                    },
+                    |_| {},
+                )
+                .unwrap()
+        };
+        let response = search(0);
+        assert!(response.hits.is_empty());
+        assert_eq!(
+            HitsInfo::Page {
+                page: 0,
+                hits_per_page: 2,
+                total_hits: 5,
+                total_pages: 3,
+            },
+            response.hits_info
+        );
+        assert_eq!(2, search(1).hits.len());
+        assert_eq!(1, search(3).hits.len());
+    }
+
+    #[test]
+    fn search_only_returns_displayed_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index_with_documents(
+            dir.path(),
+            IndexSettings {
+                primary_key: Some("id".to_owned()),
+                displayed_fields: Some(vec!["id".to_owned(), "genre".to_owned()]),
+                ..Default::default()
+            },
+        );
+
+        let rtxn = index.read();
+        let response = index
+            .search_documents(
+                &rtxn,
+                SearchOptions {
+                    attributes_to_highlight: vec!["*".to_owned()],
+                    ..Default::default()
+                },
+                |search| {
+                    search.query("rock");
+                },
+            )
+            .unwrap();
+        let hit = &response.hits[0];
+        assert!(!hit.document.contains_key("secret"));
+        assert!(!hit.formatted.as_ref().unwrap().contains_key("secret"));
+        assert_eq!("rock", hit.document["genre"]);
     }
 }
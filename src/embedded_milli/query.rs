@@ -0,0 +1,705 @@
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use anyhow::Result;
+use milli::{heed, AscDesc, Filter, TermsMatchingStrategy};
+use thiserror::Error;
+
+use super::{EmbeddedMilli, Pagination, SearchOptions, SearchResponse};
+
+const GEO_FIELD: &str = "_geo";
+
+/// Errors caused by a [`SearchQuery`] that doesn't match the index settings.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    #[error(
+        "Field `{field}` is not filterable. Filterable fields are: {}",
+        .filterable_fields.join(", ")
+    )]
+    FieldNotFilterable {
+        field: String,
+        filterable_fields: Vec<String>,
+    },
+    #[error(
+        "Field `{field}` is not sortable. Sortable fields are: {}",
+        .sortable_fields.join(", ")
+    )]
+    FieldNotSortable {
+        field: String,
+        sortable_fields: Vec<String>,
+    },
+    #[error("`{operator}` filter groups need at least one expression")]
+    EmptyFilterGroup { operator: &'static str },
+    #[error("`in` filter on field `{field}` needs at least one value")]
+    EmptyInFilter { field: String },
+    #[error("Filter on field `{field}` has a value that isn't a finite number: {value}")]
+    NonFiniteValue { field: String, value: String },
+    #[error("`{value}` can't be used in a filter because it ends with a backslash")]
+    TrailingBackslash { value: String },
+}
+
+/// A value to compare a field against in a [`FilterExpr`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl From<&str> for FilterValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_owned())
+    }
+}
+
+impl From<String> for FilterValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<i64> for FilterValue {
+    fn from(value: i64) -> Self {
+        Self::Integer(value)
+    }
+}
+
+impl From<f64> for FilterValue {
+    fn from(value: f64) -> Self {
+        Self::Float(value)
+    }
+}
+
+impl From<bool> for FilterValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl fmt::Display for FilterValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::String(value) => write!(f, "{}", Quoted(value)),
+            Self::Integer(value) => write!(f, "{value}"),
+            Self::Float(value) => write!(f, "{value}"),
+            Self::Bool(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// A filter expression that's rendered into milli's filter syntax.
+///
+/// ```ignore
+/// FilterExpr::eq("genre", "rock").and(FilterExpr::between("year", 1970, 1979));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterExpr {
+    Eq(String, FilterValue),
+    NotEq(String, FilterValue),
+    In(String, Vec<FilterValue>),
+    GreaterThan(String, FilterValue),
+    GreaterThanOrEqual(String, FilterValue),
+    LessThan(String, FilterValue),
+    LessThanOrEqual(String, FilterValue),
+    /// Inclusive range between the two values.
+    Between(String, FilterValue, FilterValue),
+    Exists(String),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+    Not(Box<FilterExpr>),
+    /// Documents whose `_geo` point is within `distance_in_meters` of the given point.
+    GeoRadius {
+        lat: f64,
+        lng: f64,
+        distance_in_meters: f64,
+    },
+    /// Documents whose `_geo` point is within the box. Points are `(lat, lng)` pairs.
+    GeoBoundingBox {
+        top_right: (f64, f64),
+        bottom_left: (f64, f64),
+    },
+}
+
+impl FilterExpr {
+    pub fn eq(field: impl Into<String>, value: impl Into<FilterValue>) -> Self {
+        Self::Eq(field.into(), value.into())
+    }
+
+    pub fn not_eq(field: impl Into<String>, value: impl Into<FilterValue>) -> Self {
+        Self::NotEq(field.into(), value.into())
+    }
+
+    pub fn is_in<V: Into<FilterValue>>(
+        field: impl Into<String>,
+        values: impl IntoIterator<Item = V>,
+    ) -> Self {
+        Self::In(field.into(), values.into_iter().map(Into::into).collect())
+    }
+
+    pub fn gt(field: impl Into<String>, value: impl Into<FilterValue>) -> Self {
+        Self::GreaterThan(field.into(), value.into())
+    }
+
+    pub fn gte(field: impl Into<String>, value: impl Into<FilterValue>) -> Self {
+        Self::GreaterThanOrEqual(field.into(), value.into())
+    }
+
+    pub fn lt(field: impl Into<String>, value: impl Into<FilterValue>) -> Self {
+        Self::LessThan(field.into(), value.into())
+    }
+
+    pub fn lte(field: impl Into<String>, value: impl Into<FilterValue>) -> Self {
+        Self::LessThanOrEqual(field.into(), value.into())
+    }
+
+    pub fn between(
+        field: impl Into<String>,
+        from: impl Into<FilterValue>,
+        to: impl Into<FilterValue>,
+    ) -> Self {
+        Self::Between(field.into(), from.into(), to.into())
+    }
+
+    pub fn exists(field: impl Into<String>) -> Self {
+        Self::Exists(field.into())
+    }
+
+    pub fn geo_radius(lat: f64, lng: f64, distance_in_meters: f64) -> Self {
+        Self::GeoRadius {
+            lat,
+            lng,
+            distance_in_meters,
+        }
+    }
+
+    pub fn geo_bounding_box(top_right: (f64, f64), bottom_left: (f64, f64)) -> Self {
+        Self::GeoBoundingBox {
+            top_right,
+            bottom_left,
+        }
+    }
+
+    pub fn and(self, other: FilterExpr) -> Self {
+        match self {
+            Self::And(mut exprs) => {
+                exprs.push(other);
+                Self::And(exprs)
+            }
+            expr => Self::And(vec![expr, other]),
+        }
+    }
+
+    pub fn or(self, other: FilterExpr) -> Self {
+        match self {
+            Self::Or(mut exprs) => {
+                exprs.push(other);
+                Self::Or(exprs)
+            }
+            expr => Self::Or(vec![expr, other]),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self {
+        Self::Not(Box::new(self))
+    }
+
+    /// Checks that every field in the expression is filterable.
+    pub fn validate(&self, filterable_fields: &HashSet<String>) -> Result<(), QueryError> {
+        let check_field = |field: &str, values: &[&FilterValue]| {
+            check_filterable(field, filterable_fields)?;
+            check_quotable(field)?;
+            for value in values {
+                if let FilterValue::String(value) = value {
+                    check_quotable(value)?;
+                }
+            }
+            check_finite(
+                field,
+                values.iter().filter_map(|value| match value {
+                    FilterValue::Float(value) => Some(*value),
+                    _ => None,
+                }),
+            )
+        };
+
+        match self {
+            Self::Eq(field, value)
+            | Self::NotEq(field, value)
+            | Self::GreaterThan(field, value)
+            | Self::GreaterThanOrEqual(field, value)
+            | Self::LessThan(field, value)
+            | Self::LessThanOrEqual(field, value) => check_field(field, &[value]),
+            Self::Between(field, from, to) => check_field(field, &[from, to]),
+            Self::Exists(field) => check_field(field, &[]),
+            Self::In(field, values) => {
+                if values.is_empty() {
+                    return Err(QueryError::EmptyInFilter {
+                        field: field.clone(),
+                    });
+                }
+                check_field(field, &values.iter().collect::<Vec<_>>())
+            }
+            Self::And(exprs) | Self::Or(exprs) => {
+                if exprs.is_empty() {
+                    return Err(QueryError::EmptyFilterGroup {
+                        operator: if matches!(self, Self::And(_)) {
+                            "and"
+                        } else {
+                            "or"
+                        },
+                    });
+                }
+                exprs
+                    .iter()
+                    .try_for_each(|expr| expr.validate(filterable_fields))
+            }
+            Self::Not(expr) => expr.validate(filterable_fields),
+            Self::GeoRadius {
+                lat,
+                lng,
+                distance_in_meters,
+            } => {
+                check_filterable(GEO_FIELD, filterable_fields)?;
+                check_finite(GEO_FIELD, [*lat, *lng, *distance_in_meters])
+            }
+            Self::GeoBoundingBox {
+                top_right,
+                bottom_left,
+            } => {
+                check_filterable(GEO_FIELD, filterable_fields)?;
+                check_finite(
+                    GEO_FIELD,
+                    [top_right.0, top_right.1, bottom_left.0, bottom_left.1],
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for FilterExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let write_group = |f: &mut fmt::Formatter<'_>, exprs: &[FilterExpr], operator: &str| {
+            for (i, expr) in exprs.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {operator} ")?;
+                }
+                write!(f, "({expr})")?;
+            }
+            Ok(())
+        };
+
+        match self {
+            Self::Eq(field, value) => write!(f, "{} = {value}", Quoted(field)),
+            Self::NotEq(field, value) => write!(f, "{} != {value}", Quoted(field)),
+            Self::In(field, values) => {
+                let values: Vec<_> = values.iter().map(ToString::to_string).collect();
+                write!(f, "{} IN [{}]", Quoted(field), values.join(", "))
+            }
+            Self::GreaterThan(field, value) => write!(f, "{} > {value}", Quoted(field)),
+            Self::GreaterThanOrEqual(field, value) => write!(f, "{} >= {value}", Quoted(field)),
+            Self::LessThan(field, value) => write!(f, "{} < {value}", Quoted(field)),
+            Self::LessThanOrEqual(field, value) => write!(f, "{} <= {value}", Quoted(field)),
+            Self::Between(field, from, to) => write!(f, "{} {from} TO {to}", Quoted(field)),
+            Self::Exists(field) => write!(f, "{} EXISTS", Quoted(field)),
+            Self::And(exprs) => write_group(f, exprs, "AND"),
+            Self::Or(exprs) => write_group(f, exprs, "OR"),
+            Self::Not(expr) => write!(f, "NOT ({expr})"),
+            Self::GeoRadius {
+                lat,
+                lng,
+                distance_in_meters,
+            } => write!(f, "_geoRadius({lat}, {lng}, {distance_in_meters})"),
+            Self::GeoBoundingBox {
+                top_right,
+                bottom_left,
+            } => write!(
+                f,
+                "_geoBoundingBox([{}, {}], [{}, {}])",
+                top_right.0, top_right.1, bottom_left.0, bottom_left.1
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SortCriterion {
+    Asc(String),
+    Desc(String),
+    /// Sorts by distance from the point, closest first.
+    GeoPointAsc {
+        lat: f64,
+        lng: f64,
+    },
+    /// Sorts by distance from the point, farthest first.
+    GeoPointDesc {
+        lat: f64,
+        lng: f64,
+    },
+}
+
+impl SortCriterion {
+    fn field(&self) -> &str {
+        match self {
+            Self::Asc(field) | Self::Desc(field) => field,
+            Self::GeoPointAsc { .. } | Self::GeoPointDesc { .. } => GEO_FIELD,
+        }
+    }
+}
+
+impl fmt::Display for SortCriterion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Asc(field) => write!(f, "{field}:asc"),
+            Self::Desc(field) => write!(f, "{field}:desc"),
+            Self::GeoPointAsc { lat, lng } => write!(f, "_geoPoint({lat}, {lng}):asc"),
+            Self::GeoPointDesc { lat, lng } => write!(f, "_geoPoint({lat}, {lng}):desc"),
+        }
+    }
+}
+
+/// How many of the query words a document needs to contain to be returned.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MatchingStrategy {
+    /// Drops query words from the end until documents are found.
+    #[default]
+    Last,
+    /// Only returns documents that contain every query word.
+    All,
+}
+
+impl From<MatchingStrategy> for TermsMatchingStrategy {
+    fn from(strategy: MatchingStrategy) -> Self {
+        match strategy {
+            MatchingStrategy::Last => TermsMatchingStrategy::Last,
+            MatchingStrategy::All => TermsMatchingStrategy::All,
+        }
+    }
+}
+
+/// A search request that's checked against the index settings before it runs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub query: Option<String>,
+    pub filter: Option<FilterExpr>,
+    pub sort: Vec<SortCriterion>,
+    pub matching_strategy: MatchingStrategy,
+    pub options: SearchOptions,
+}
+
+impl SearchQuery {
+    pub fn new(query: impl Into<String>) -> Self {
+        Self {
+            query: Some(query.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Adds a filter. Calling this more than once requires every filter to match.
+    pub fn with_filter(mut self, filter: FilterExpr) -> Self {
+        self.filter = Some(match self.filter {
+            Some(existing) => existing.and(filter),
+            None => filter,
+        });
+        self
+    }
+
+    /// Adds a sort criterion. Earlier criteria take precedence over later ones.
+    pub fn with_sort(mut self, criterion: SortCriterion) -> Self {
+        self.sort.push(criterion);
+        self
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        let offset = match self.options.pagination {
+            Pagination::OffsetLimit { offset, .. } => offset,
+            Pagination::Page { .. } => 0,
+        };
+        self.options.pagination = Pagination::OffsetLimit { offset, limit };
+        self
+    }
+
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        let limit = match self.options.pagination {
+            Pagination::OffsetLimit { limit, .. } => limit,
+            Pagination::Page { hits_per_page, .. } => hits_per_page,
+        };
+        self.options.pagination = Pagination::OffsetLimit { offset, limit };
+        self
+    }
+
+    pub fn with_matching_strategy(mut self, matching_strategy: MatchingStrategy) -> Self {
+        self.matching_strategy = matching_strategy;
+        self
+    }
+
+    pub fn with_options(mut self, options: SearchOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Checks that the filter and sort fields are allowed by the index settings.
+    pub fn validate(
+        &self,
+        filterable_fields: &HashSet<String>,
+        sortable_fields: &HashSet<String>,
+    ) -> Result<(), QueryError> {
+        if let Some(filter) = &self.filter {
+            filter.validate(filterable_fields)?;
+        }
+        for criterion in &self.sort {
+            let field = criterion.field();
+            if !is_field_allowed(field, sortable_fields) {
+                return Err(QueryError::FieldNotSortable {
+                    field: field.to_owned(),
+                    sortable_fields: sorted_fields(sortable_fields),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EmbeddedMilli {
+    /// Runs a [`SearchQuery`] after validating it against the index settings.
+    pub fn search(&self, rtxn: &heed::RoTxn, query: &SearchQuery) -> Result<SearchResponse> {
+        query.validate(
+            &self.index.filterable_fields(rtxn)?,
+            &self.index.sortable_fields(rtxn)?,
+        )?;
+
+        let filter_expr = query.filter.as_ref().map(ToString::to_string);
+        let filter = match &filter_expr {
+            Some(filter_expr) => Filter::from_str(filter_expr)?,
+            None => None,
+        };
+        let sort_criteria = query
+            .sort
+            .iter()
+            .map(|criterion| AscDesc::from_str(&criterion.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.search_documents(rtxn, query.options.clone(), |search| {
+            if let Some(text) = &query.query {
+                search.query(text);
+            }
+            if let Some(filter) = filter {
+                search.filter(filter);
+            }
+            if !sort_criteria.is_empty() {
+                search.sort_criteria(sort_criteria);
+            }
+            search.terms_matching_strategy(query.matching_strategy.into());
+        })
+    }
+}
+
+// Renders a field name or string value as a double-quoted token for milli's filter parser
+struct Quoted<'a>(&'a str);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The parser only unescapes quotes, so any other backslash is kept as it is
+        write!(f, "\"{}\"", self.0.replace('"', "\\\""))
+    }
+}
+
+// A trailing backslash would escape the closing quote, and there's no way to escape it
+fn check_quotable(value: &str) -> Result<(), QueryError> {
+    if value.ends_with('\\') {
+        Err(QueryError::TrailingBackslash {
+            value: value.to_owned(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+// NaN and infinity would be rendered as words that milli can't parse as numbers
+fn check_finite(field: &str, values: impl IntoIterator<Item = f64>) -> Result<(), QueryError> {
+    match values.into_iter().find(|value| !value.is_finite()) {
+        Some(value) => Err(QueryError::NonFiniteValue {
+            field: field.to_owned(),
+            value: value.to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Checks that the field, or one of its parents, is filterable.
+pub(super) fn check_filterable(
+    field: &str,
+    filterable_fields: &HashSet<String>,
+) -> Result<(), QueryError> {
+    if is_field_allowed(field, filterable_fields) {
+        Ok(())
+    } else {
+        Err(QueryError::FieldNotFilterable {
+            field: field.to_owned(),
+            filterable_fields: sorted_fields(filterable_fields),
+        })
+    }
+}
+
+// Nested fields are allowed when one of their parents is in the list
+fn is_field_allowed(field: &str, allowed_fields: &HashSet<String>) -> bool {
+    allowed_fields.iter().any(|allowed| {
+        field == allowed
+            || field
+                .strip_prefix(allowed.as_str())
+                .is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
+fn sorted_fields(fields: &HashSet<String>) -> Vec<String> {
+    let mut fields: Vec<_> = fields.iter().cloned().collect();
+    fields.sort();
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::embedded_milli::{DocumentsQuery, IndexSettings, Instance};
+
+    fn fields(fields: &[&str]) -> HashSet<String> {
+        fields.iter().map(|field| field.to_string()).collect()
+    }
+
+    #[test]
+    fn renders_quoted_fields_and_values() {
+        assert_eq!(
+            r#""genre" = "rock""#,
+            FilterExpr::eq("genre", "rock").to_string()
+        );
+        assert_eq!(
+            r#""title" != "say \"hi\"""#,
+            FilterExpr::not_eq("title", "say \"hi\"").to_string()
+        );
+        assert_eq!(
+            r#""path" = "C:\music""#,
+            FilterExpr::eq("path", r"C:\music").to_string()
+        );
+        assert_eq!(
+            r#""album.year" >= 1970"#,
+            FilterExpr::gte("album.year", 1970_i64).to_string()
+        );
+        assert_eq!(r#""live" EXISTS"#, FilterExpr::exists("live").to_string());
+    }
+
+    #[test]
+    fn renders_in_and_between() {
+        assert_eq!(
+            r#""genre" IN ["rock", "jazz"]"#,
+            FilterExpr::is_in("genre", ["rock", "jazz"]).to_string()
+        );
+        assert_eq!(
+            r#""year" 1970 TO 1979.5"#,
+            FilterExpr::between("year", 1970_i64, 1979.5).to_string()
+        );
+    }
+
+    #[test]
+    fn renders_groups() {
+        assert_eq!(
+            r#"NOT (("live" = true) OR ("year" < 1970))"#,
+            FilterExpr::eq("live", true)
+                .or(FilterExpr::lt("year", 1970_i64))
+                .not()
+                .to_string()
+        );
+        assert_eq!(
+            r#"("a" = 1) AND ("b" = 2) AND ("c" = 3)"#,
+            FilterExpr::eq("a", 1_i64)
+                .and(FilterExpr::eq("b", 2_i64))
+                .and(FilterExpr::eq("c", 3_i64))
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn renders_geo_filters() {
+        assert_eq!(
+            "_geoRadius(45.5, -73.5, 1000)",
+            FilterExpr::geo_radius(45.5, -73.5, 1000.0).to_string()
+        );
+        assert_eq!(
+            "_geoBoundingBox([46, -73], [45.5, -74.5])",
+            FilterExpr::geo_bounding_box((46.0, -73.0), (45.5, -74.5)).to_string()
+        );
+    }
+
+    #[test]
+    fn rejects_non_finite_numbers() {
+        assert_eq!(
+            Err(QueryError::NonFiniteValue {
+                field: "score".to_owned(),
+                value: "NaN".to_owned(),
+            }),
+            FilterExpr::gt("score", f64::NAN).validate(&fields(&["score"]))
+        );
+        assert_eq!(
+            Err(QueryError::NonFiniteValue {
+                field: GEO_FIELD.to_owned(),
+                value: "inf".to_owned(),
+            }),
+            FilterExpr::geo_radius(45.5, -73.5, f64::INFINITY).validate(&fields(&[GEO_FIELD]))
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_backslash() {
+        assert_eq!(
+            Err(QueryError::TrailingBackslash {
+                value: r"C:\".to_owned(),
+            }),
+            FilterExpr::eq("path", r"C:\").validate(&fields(&["path"]))
+        );
+    }
+
+    #[test]
+    fn filters_on_quotes_and_backslashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = Instance::new(dir.path()).get_index("test").unwrap();
+        let mut wtxn = index.write();
+        index
+            .set_settings(
+                &mut wtxn,
+                IndexSettings {
+                    primary_key: Some("id".to_owned()),
+                    filterable_fields: vec!["name".to_owned()],
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        index
+            .add_documents(
+                &mut wtxn,
+                vec![
+                    json!({ "id": 1, "name": r#"say "hi" \o/"# }),
+                    json!({ "id": 2, "name": "say hi" }),
+                ]
+                .into_iter()
+                .map(|document| document.as_object().unwrap().clone())
+                .collect(),
+            )
+            .unwrap();
+        index.commit(wtxn).unwrap();
+
+        let rtxn = index.read();
+        let ids: Vec<_> = index
+            .documents(
+                &rtxn,
+                &DocumentsQuery {
+                    filter: Some(FilterExpr::eq("name", r#"say "hi" \o/"#)),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .map(|document| document.unwrap()["id"].clone())
+            .collect();
+        assert_eq!(vec![json!(1)], ids);
+    }
+}
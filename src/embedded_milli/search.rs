@@ -0,0 +1,348 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use derivative::Derivative;
+use milli::{
+    heed, score_details::ScoreDetails, tokenizer::TokenizerBuilder, FormatOptions, MatcherBuilder,
+    Search, SearchResult,
+};
+
+use super::{Document, EmbeddedMilli};
+
+// Control characters used to mark the matched words in the formatted output so they can be
+// found again or swapped for the highlight tags. Nothing stops them from appearing in indexed
+// text, so they're stripped from it before formatting.
+const MATCH_START: char = '\u{1}';
+const MATCH_END: char = '\u{2}';
+
+/// Selects which page of the search results is returned.
+///
+/// `OffsetLimit` only computes an estimate of the total number of hits, while `Page` computes
+/// the exact count so the total number of pages is known.
+/// Pages start at 1. Requesting page 0 returns no hits, but the totals are still computed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pagination {
+    OffsetLimit { offset: usize, limit: usize },
+    Page { page: usize, hits_per_page: usize },
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self::OffsetLimit {
+            offset: 0,
+            limit: 20,
+        }
+    }
+}
+
+/// Options that control the shape of a [`SearchResponse`].
+///
+/// When `attributes_to_highlight` or `attributes_to_crop` contain any fields (or `"*"` for all
+/// fields), every hit includes a `formatted` copy of its document. Highlighted fields have
+/// their matched words wrapped in the pre/post tags, and cropped fields are shortened to
+/// `crop_length` words around the matches, with `crop_marker` marking the cut. The control
+/// characters U+0001 and U+0002 are used internally to mark matches, so they're removed from
+/// the formatted values.
+///
+/// Collecting the [`matched_words`](SearchResponse::matched_words) means formatting every
+/// field of every hit, so it's only done when `collect_matched_words` is set.
+///
+/// The index settings are applied on top of these options: hits only contain the
+/// [`displayed_fields`](super::IndexSettings::displayed_fields), and pagination stops at
+/// [`pagination_max_total_hits`](super::IndexSettings::pagination_max_total_hits).
+#[derive(Clone, Debug, PartialEq, Eq, Derivative)]
+#[derivative(Default)]
+pub struct SearchOptions {
+    pub pagination: Pagination,
+    pub attributes_to_highlight: Vec<String>,
+    pub attributes_to_crop: Vec<String>,
+    #[derivative(Default(value = "10"))]
+    pub crop_length: usize,
+    #[derivative(Default(value = "\"…\".to_owned()"))]
+    pub crop_marker: String,
+    #[derivative(Default(value = "\"<em>\".to_owned()"))]
+    pub highlight_pre_tag: String,
+    #[derivative(Default(value = "\"</em>\".to_owned()"))]
+    pub highlight_post_tag: String,
+    pub collect_matched_words: bool,
+}
+
+/// Total hit counts for the requested [`Pagination`] mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HitsInfo {
+    OffsetLimit {
+        offset: usize,
+        limit: usize,
+        estimated_total_hits: usize,
+    },
+    Page {
+        page: usize,
+        hits_per_page: usize,
+        total_hits: usize,
+        total_pages: usize,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchHit<D = Document> {
+    pub document: D,
+    /// Relevancy of the hit between 0 and 1.
+    pub ranking_score: f64,
+    /// The document with highlighting and cropping applied, if any were requested.
+    pub formatted: Option<Document>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResponse<D = Document> {
+    pub hits: Vec<SearchHit<D>>,
+    pub hits_info: HitsInfo,
+    pub processing_time: Duration,
+    /// Words from the hits that matched the query, after typo tolerance and prefix matching.
+    /// Only filled in when [`SearchOptions::collect_matched_words`] is set.
+    pub matched_words: Vec<String>,
+}
+
+impl<D> SearchResponse<D> {
+    /// Converts the document of every hit, keeping the rest of the response as is.
+    pub fn map_documents<E>(self, mut f: impl FnMut(D) -> E) -> SearchResponse<E> {
+        SearchResponse {
+            hits: self
+                .hits
+                .into_iter()
+                .map(|hit| SearchHit {
+                    document: f(hit.document),
+                    ranking_score: hit.ranking_score,
+                    formatted: hit.formatted,
+                })
+                .collect(),
+            hits_info: self.hits_info,
+            processing_time: self.processing_time,
+            matched_words: self.matched_words,
+        }
+    }
+}
+
+impl EmbeddedMilli {
+    pub fn search_documents(
+        &self,
+        rtxn: &heed::RoTxn,
+        options: SearchOptions,
+        build_search: impl FnOnce(&mut Search),
+    ) -> Result<SearchResponse> {
+        let started_at = Instant::now();
+
+        // Create the search
+        let mut search = Search::new(rtxn, &self.index);
+        build_search(&mut search);
+        let (offset, limit) = match options.pagination {
+            Pagination::OffsetLimit { offset, limit } => (offset, limit),
+            Pagination::Page {
+                page,
+                hits_per_page,
+            } => {
+                search.exhaustive_number_hits(true);
+                match page.checked_sub(1) {
+                    Some(page) => (page.saturating_mul(hits_per_page), hits_per_page),
+                    // Pages start at 1, page 0 only returns the totals
+                    None => (0, 0),
+                }
+            }
+        };
+        let max_total_hits = self
+            .index
+            .pagination_max_total_hits(rtxn)?
+            .unwrap_or(usize::MAX);
+        let offset = offset.min(max_total_hits);
+        search.offset(offset);
+        search.limit(limit.min(max_total_hits - offset));
+
+        // Get the documents based on the search results
+        let SearchResult {
+            matching_words,
+            candidates,
+            document_scores,
+            documents_ids,
+        } = search.execute()?;
+        let fields_ids_map = self.index.fields_ids_map(rtxn)?;
+        let displayed_fields = self.index.displayed_fields(rtxn)?;
+        let documents = self
+            .index
+            .documents(rtxn, documents_ids)?
+            .iter()
+            .map(|(_id, doc)| {
+                let mut document = milli::all_obkv_to_json(*doc, &fields_ids_map)?;
+                if let Some(displayed_fields) = &displayed_fields {
+                    document.retain(|field, _| displayed_fields.contains(&field.as_str()));
+                }
+                Ok(document)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut tokenizer_builder = TokenizerBuilder::default();
+        tokenizer_builder.create_char_map(true);
+        let mut matcher_builder = MatcherBuilder::new(matching_words, tokenizer_builder.build());
+        matcher_builder.highlight_prefix(MATCH_START.to_string());
+        matcher_builder.highlight_suffix(MATCH_END.to_string());
+        matcher_builder.crop_marker(options.crop_marker.clone());
+
+        let mut matched_words = BTreeSet::new();
+        if options.collect_matched_words {
+            for document in &documents {
+                for value in document.values() {
+                    collect_matched_words(&matcher_builder, value, &mut matched_words);
+                }
+            }
+        }
+
+        let hits = documents
+            .into_iter()
+            .zip(document_scores)
+            .map(|(document, scores)| SearchHit {
+                formatted: format_document(&matcher_builder, &document, &options),
+                document,
+                ranking_score: ScoreDetails::global_score(scores.iter()),
+            })
+            .collect();
+
+        let total_hits = (candidates.len() as usize).min(max_total_hits);
+        let hits_info = match options.pagination {
+            Pagination::OffsetLimit { offset, limit } => HitsInfo::OffsetLimit {
+                offset,
+                limit,
+                estimated_total_hits: total_hits,
+            },
+            Pagination::Page {
+                page,
+                hits_per_page,
+            } => HitsInfo::Page {
+                page,
+                hits_per_page,
+                total_hits,
+                total_pages: if hits_per_page == 0 {
+                    0
+                } else {
+                    total_hits.div_ceil(hits_per_page)
+                },
+            },
+        };
+
+        Ok(SearchResponse {
+            hits,
+            hits_info,
+            processing_time: started_at.elapsed(),
+            matched_words: matched_words.into_iter().collect(),
+        })
+    }
+}
+
+fn format_document<A: AsRef<[u8]>>(
+    matcher_builder: &MatcherBuilder<'_, A>,
+    document: &Document,
+    options: &SearchOptions,
+) -> Option<Document> {
+    if options.attributes_to_highlight.is_empty() && options.attributes_to_crop.is_empty() {
+        return None;
+    }
+    let contains_field =
+        |fields: &[String], field: &str| fields.iter().any(|f| f == "*" || f == field);
+
+    let formatted = document
+        .iter()
+        .map(|(field, value)| {
+            let format_options = FormatOptions {
+                highlight: contains_field(&options.attributes_to_highlight, field),
+                crop: contains_field(&options.attributes_to_crop, field)
+                    .then_some(options.crop_length),
+            };
+            let value = if format_options.should_format() {
+                format_value(matcher_builder, value, format_options, options)
+            } else {
+                value.clone()
+            };
+            (field.clone(), value)
+        })
+        .collect();
+    Some(formatted)
+}
+
+fn format_value<A: AsRef<[u8]>>(
+    matcher_builder: &MatcherBuilder<'_, A>,
+    value: &serde_json::Value,
+    format_options: FormatOptions,
+    options: &SearchOptions,
+) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(text) => {
+            let text = strip_markers(text);
+            let mut matcher = matcher_builder.build(&text);
+            let formatted = matcher
+                .format(format_options)
+                .replace(MATCH_START, &options.highlight_pre_tag)
+                .replace(MATCH_END, &options.highlight_post_tag);
+            serde_json::Value::String(formatted)
+        }
+        serde_json::Value::Array(values) => serde_json::Value::Array(
+            values
+                .iter()
+                .map(|value| format_value(matcher_builder, value, format_options, options))
+                .collect(),
+        ),
+        serde_json::Value::Object(values) => serde_json::Value::Object(
+            values
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        key.clone(),
+                        format_value(matcher_builder, value, format_options, options),
+                    )
+                })
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+fn strip_markers(text: &str) -> Cow<'_, str> {
+    if text.contains([MATCH_START, MATCH_END]) {
+        Cow::Owned(text.replace([MATCH_START, MATCH_END], ""))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+fn collect_matched_words<A: AsRef<[u8]>>(
+    matcher_builder: &MatcherBuilder<'_, A>,
+    value: &serde_json::Value,
+    matched_words: &mut BTreeSet<String>,
+) {
+    match value {
+        serde_json::Value::String(text) => {
+            let text = strip_markers(text);
+            let mut matcher = matcher_builder.build(&text);
+            let formatted = matcher.format(FormatOptions {
+                highlight: true,
+                crop: None,
+            });
+            for part in formatted.split(MATCH_START).skip(1) {
+                if let Some((word, _)) = part.split_once(MATCH_END) {
+                    matched_words.insert(word.to_owned());
+                }
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                collect_matched_words(matcher_builder, value, matched_words);
+            }
+        }
+        serde_json::Value::Object(values) => {
+            for value in values.values() {
+                collect_matched_words(matcher_builder, value, matched_words);
+            }
+        }
+        _ => {}
+    }
+}
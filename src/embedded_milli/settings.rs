@@ -0,0 +1,231 @@
+use std::{str::FromStr, sync::atomic::Ordering};
+
+use anyhow::Result;
+use milli::{heed, update};
+
+use super::{EmbeddedMilli, IndexSettings, Synonyms};
+
+/// A change to a single setting, mirroring milli's `Setting`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Setting<T> {
+    Set(T),
+    /// Restores milli's default value.
+    Reset,
+    /// Keeps the current value.
+    #[default]
+    NotSet,
+}
+
+impl<T> Setting<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Setting<U> {
+        match self {
+            Self::Set(value) => Setting::Set(f(value)),
+            Self::Reset => Setting::Reset,
+            Self::NotSet => Setting::NotSet,
+        }
+    }
+}
+
+impl<T> From<Option<T>> for Setting<T> {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => Self::Set(value),
+            None => Self::Reset,
+        }
+    }
+}
+
+/// A partial update of the index settings. Fields left as [`Setting::NotSet`] keep their
+/// current value.
+///
+/// ```ignore
+/// index.update_settings(&mut wtxn, SettingsPatch {
+///     stop_words: Setting::Set(vec!["the".to_owned()]),
+///     ..Default::default()
+/// })?;
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SettingsPatch {
+    pub primary_key: Setting<String>,
+    pub searchable_fields: Setting<Vec<String>>,
+    pub filterable_fields: Setting<Vec<String>>,
+    pub sortable_fields: Setting<Vec<String>>,
+    pub ranking_rules: Setting<Vec<String>>,
+    pub stop_words: Setting<Vec<String>>,
+    pub synonyms: Setting<Vec<Synonyms>>,
+    pub typos_enabled: Setting<bool>,
+    pub min_word_size_for_one_typo: Setting<u8>,
+    pub min_word_size_for_two_typos: Setting<u8>,
+    pub disallow_typos_on_words: Setting<Vec<String>>,
+    pub disallow_typos_on_fields: Setting<Vec<String>>,
+    pub displayed_fields: Setting<Vec<String>>,
+    pub distinct_attribute: Setting<String>,
+    pub max_values_per_facet: Setting<usize>,
+    pub pagination_max_total_hits: Setting<usize>,
+}
+
+impl From<IndexSettings> for SettingsPatch {
+    fn from(settings: IndexSettings) -> Self {
+        Self {
+            primary_key: settings.primary_key.into(),
+            searchable_fields: settings.searchable_fields.into(),
+            filterable_fields: Setting::Set(settings.filterable_fields),
+            sortable_fields: Setting::Set(settings.sortable_fields),
+            ranking_rules: Setting::Set(settings.ranking_rules),
+            stop_words: Setting::Set(settings.stop_words),
+            synonyms: Setting::Set(settings.synonyms),
+            typos_enabled: Setting::Set(settings.typos_enabled),
+            // The typo word sizes are always read back as `Some`, so `None` means they weren't
+            // specified rather than that they should be reset
+            min_word_size_for_one_typo: settings
+                .min_word_size_for_one_typo
+                .map_or(Setting::NotSet, Setting::Set),
+            min_word_size_for_two_typos: settings
+                .min_word_size_for_two_typos
+                .map_or(Setting::NotSet, Setting::Set),
+            disallow_typos_on_words: Setting::Set(settings.disallow_typos_on_words),
+            disallow_typos_on_fields: Setting::Set(settings.disallow_typos_on_fields),
+            displayed_fields: settings.displayed_fields.into(),
+            distinct_attribute: settings.distinct_attribute.into(),
+            max_values_per_facet: settings.max_values_per_facet.into(),
+            pagination_max_total_hits: settings.pagination_max_total_hits.into(),
+        }
+    }
+}
+
+impl EmbeddedMilli {
+    /// Applies the patch to the index settings, leaving any [`Setting::NotSet`] fields as they
+    /// are. Commit the transaction with [`commit`](Self::commit) so the copy of the settings
+    /// that's used to recreate the index is kept up to date.
+    pub fn update_settings<'t>(
+        &'t self,
+        wtxn: &mut heed::RwTxn<'t, '_>,
+        patch: SettingsPatch,
+    ) -> Result<()> {
+        // Destructure the patch into the corresponding fields
+        let SettingsPatch {
+            primary_key,
+            searchable_fields,
+            filterable_fields,
+            sortable_fields,
+            ranking_rules,
+            stop_words,
+            synonyms,
+            typos_enabled,
+            min_word_size_for_one_typo,
+            min_word_size_for_two_typos,
+            disallow_typos_on_words,
+            disallow_typos_on_fields,
+            displayed_fields,
+            distinct_attribute,
+            max_values_per_facet,
+            pagination_max_total_hits,
+        } = patch;
+
+        // Parse the ranking rules up front so an invalid rule doesn't leave a partial update
+        let ranking_rules = match ranking_rules {
+            Setting::Set(rules) => Setting::Set(
+                rules
+                    .iter()
+                    .map(String::as_str)
+                    .map(milli::Criterion::from_str)
+                    .map(|r| r.map_err(anyhow::Error::from))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            Setting::Reset => Setting::Reset,
+            Setting::NotSet => Setting::NotSet,
+        };
+
+        // Set up the settings update
+        let indexer_config = update::IndexerConfig::default();
+        let mut builder = update::Settings::new(wtxn, &self.index, &indexer_config);
+
+        // Copy over the settings that were changed
+        macro_rules! apply {
+            ($setting:expr, $set:ident, $reset:ident) => {
+                match $setting {
+                    Setting::Set(value) => builder.$set(value),
+                    Setting::Reset => builder.$reset(),
+                    Setting::NotSet => {}
+                }
+            };
+            ($setting:expr, $set:ident, $reset:ident, $map:expr) => {
+                apply!($setting.map($map), $set, $reset)
+            };
+        }
+
+        apply!(primary_key, set_primary_key, reset_primary_key);
+        apply!(
+            searchable_fields,
+            set_searchable_fields,
+            reset_searchable_fields
+        );
+        apply!(
+            filterable_fields,
+            set_filterable_fields,
+            reset_filterable_fields,
+            |fields| fields.into_iter().collect()
+        );
+        apply!(
+            sortable_fields,
+            set_sortable_fields,
+            reset_sortable_fields,
+            |fields| fields.into_iter().collect()
+        );
+        apply!(ranking_rules, set_criteria, reset_criteria);
+        apply!(stop_words, set_stop_words, reset_stop_words, |words| words
+            .into_iter()
+            .collect());
+        apply!(synonyms, set_synonyms, reset_synonyms, |synonyms| synonyms
+            .into_iter()
+            .map(|s| (s.word, s.synonyms))
+            .collect());
+        apply!(typos_enabled, set_autorize_typos, reset_authorize_typos);
+        apply!(
+            min_word_size_for_one_typo,
+            set_min_word_len_one_typo,
+            reset_min_word_len_one_typo
+        );
+        apply!(
+            min_word_size_for_two_typos,
+            set_min_word_len_two_typos,
+            reset_min_word_len_two_typos
+        );
+        apply!(
+            disallow_typos_on_words,
+            set_exact_words,
+            reset_exact_words,
+            |words| words.into_iter().collect()
+        );
+        apply!(
+            disallow_typos_on_fields,
+            set_exact_attributes,
+            reset_exact_attributes,
+            |fields| fields.into_iter().collect()
+        );
+        apply!(
+            displayed_fields,
+            set_displayed_fields,
+            reset_displayed_fields
+        );
+        apply!(distinct_attribute, set_distinct_field, reset_distinct_field);
+        apply!(
+            max_values_per_facet,
+            set_max_values_per_facet,
+            reset_max_values_per_facet
+        );
+        apply!(
+            pagination_max_total_hits,
+            set_pagination_max_total_hits,
+            reset_pagination_max_total_hits
+        );
+
+        // Execute the settings update
+        builder.execute(|_| {}, || false)?;
+
+        // The copy kept for recreating the index is only written once the change is committed
+        self.settings_changed.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+}
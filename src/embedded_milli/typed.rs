@@ -0,0 +1,64 @@
+use anyhow::{anyhow, Result};
+use milli::heed;
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use super::{Document, EmbeddedMilli, SearchQuery, SearchResponse};
+
+/// A document that couldn't be deserialized into the requested type. The raw document is
+/// kept so the caller can still inspect or log it.
+#[derive(Error, Debug)]
+#[error("Failed to deserialize document: {source}")]
+pub struct DocumentError {
+    pub document: Document,
+    pub source: serde_json::Error,
+}
+
+impl EmbeddedMilli {
+    /// Serializes the values into documents and adds them to the index. Each value needs to
+    /// serialize to a JSON object.
+    pub fn add_typed<'t, T: Serialize>(
+        &'t self,
+        wtxn: &mut heed::RwTxn<'t, '_>,
+        documents: &[T],
+    ) -> Result<()> {
+        let documents = documents
+            .iter()
+            .map(to_document)
+            .collect::<Result<Vec<_>>>()?;
+        self.add_documents(wtxn, documents)
+    }
+
+    pub fn get_typed<T: DeserializeOwned>(
+        &self,
+        rtxn: &heed::RoTxn,
+        document_id: String,
+    ) -> Result<Option<T>> {
+        self.get_document(rtxn, document_id)?
+            .map(|document| from_document(document).map_err(anyhow::Error::from))
+            .transpose()
+    }
+
+    /// Runs the query and deserializes each hit. A hit that fails to deserialize is returned
+    /// as an error without failing the rest of the search.
+    pub fn search_typed<T: DeserializeOwned>(
+        &self,
+        rtxn: &heed::RoTxn,
+        query: &SearchQuery,
+    ) -> Result<SearchResponse<Result<T, DocumentError>>> {
+        Ok(self.search(rtxn, query)?.map_documents(from_document))
+    }
+}
+
+fn to_document<T: Serialize>(value: &T) -> Result<Document> {
+    match serde_json::to_value(value)? {
+        serde_json::Value::Object(document) => Ok(document),
+        value => Err(anyhow!("Expected a JSON object but found {value}")),
+    }
+}
+
+fn from_document<T: DeserializeOwned>(document: Document) -> Result<T, DocumentError> {
+    // Deserializing needs an owned value, so keep a copy around in case it fails
+    serde_json::from_value(serde_json::Value::Object(document.clone()))
+        .map_err(|source| DocumentError { document, source })
+}
@@ -0,0 +1,93 @@
+use thiserror::Error;
+
+/// Errors that occur while keeping the indexes in sync with the database.
+///
+/// Failures while applying committed changes happen on the index updater thread, so they're
+/// delivered to the handlers registered with
+/// [`with_error_handler`](crate::pool::SqliteConnectionHandler::with_error_handler). Explicitly
+/// requested operations such as rebuilds return their errors to the caller instead.
+#[derive(Error, Debug)]
+pub enum SkaldError {
+    #[error("Failed to open index {index_name}: {source}")]
+    OpenIndex {
+        index_name: String,
+        source: anyhow::Error,
+    },
+    #[error(
+        "Failed to run update query for index {index_name} (rowid {rowid}, keys {keys:?}): \
+        {source}"
+    )]
+    UpdateQuery {
+        index_name: String,
+        rowid: i64,
+        /// Keys of the other documents in the batch that were dropped along with this row.
+        keys: Vec<String>,
+        source: rusqlite::Error,
+    },
+    #[error(
+        "Failed to run update query for index {index_name} (rowids {rowids:?}, keys {keys:?}): \
+        {source}"
+    )]
+    BatchUpdateQuery {
+        index_name: String,
+        rowids: Vec<i64>,
+        /// Keys of the other documents in the batch that were dropped along with these rows.
+        keys: Vec<String>,
+        source: rusqlite::Error,
+    },
+    #[error("Failed to run rebuild query for index {index_name}: {source}")]
+    RebuildQuery {
+        index_name: String,
+        source: rusqlite::Error,
+    },
+    #[error("Failed to update index {index_name} (keys {keys:?}): {source}")]
+    Indexing {
+        index_name: String,
+        keys: Vec<String>,
+        source: anyhow::Error,
+    },
+    #[error("No registered table feeds index {index_name}")]
+    UnknownIndex { index_name: String },
+    #[error("Failed to read the database schema: {source}")]
+    SchemaQuery { source: rusqlite::Error },
+    #[error("Column {column} does not exist in table {database}.{table}")]
+    UnknownColumn {
+        database: String,
+        table: String,
+        column: String,
+    },
+    #[error("Key prefixes {prefix} and {other_prefix} of index {index_name} overlap")]
+    OverlappingKeyPrefixes {
+        index_name: String,
+        prefix: String,
+        other_prefix: String,
+    },
+    #[error("Source {entry_type} of index {index_name} has no key columns")]
+    EmptyKeyColumns {
+        index_name: String,
+        entry_type: String,
+    },
+    #[error("Failed to compute the key of a row in {database}.{table} for index {index_name}")]
+    UnresolvedKey {
+        index_name: String,
+        database: String,
+        table: String,
+    },
+    #[error("Entry type {entry_type} is used by more than one source of index {index_name}")]
+    DuplicateEntryType {
+        index_name: String,
+        entry_type: String,
+    },
+    #[error("The index updater has been shut down")]
+    Shutdown,
+}
+
+impl SkaldError {
+    /// Adds the keys of the documents that were dropped along with the failed rows.
+    pub(crate) fn with_keys(mut self, dropped_keys: impl IntoIterator<Item = String>) -> Self {
+        if let Self::UpdateQuery { keys, .. } | Self::BatchUpdateQuery { keys, .. } = &mut self {
+            keys.extend(dropped_keys);
+        }
+        self
+    }
+}
@@ -3,83 +3,423 @@ use dashmap::{
     DashMap,
 };
 use derivative::Derivative;
-use embedded_milli::Document;
+use embedded_milli::{Document, UpdateMethod};
 use rusqlite::{
-    preupdate_hook::PreUpdateOldValueAccessor,
+    preupdate_hook::{PreUpdateNewValueAccessor, PreUpdateOldValueAccessor},
     types::{FromSql, ValueRef},
-    Params, Statement,
+    Params, Row, Statement,
+};
+use std::{
+    hash::Hash,
+    sync::{
+        atomic::{AtomicI32, AtomicU64, Ordering},
+        Arc,
+    },
 };
-use std::{convert::Infallible, hash::Hash, sync::Arc};
 
 pub mod embedded_milli;
+mod error;
 pub mod pool;
+mod unified;
+
+pub use error::SkaldError;
+pub use unified::{UnifiedIndex, UnifiedSource};
+
+/// Gives access to the column values of a row that's about to be modified.
+///
+/// Implemented for both the old and the new row values so that a [`PrimaryKeyFn`] can compute
+/// the document key for either side of an update.
+pub trait RowAccessor {
+    fn column_value(&self, i: i32) -> ValueRef<'_>;
+}
 
+impl RowAccessor for PreUpdateOldValueAccessor {
+    fn column_value(&self, i: i32) -> ValueRef<'_> {
+        self.get_old_column_value(i)
+    }
+}
+
+impl RowAccessor for PreUpdateNewValueAccessor {
+    fn column_value(&self, i: i32) -> ValueRef<'_> {
+        self.get_new_column_value(i)
+    }
+}
+
+/// Computes the document key of a row from its column values.
+///
+/// Returns `None` if the key can't be computed. The change to the row is skipped and reported to
+/// the error handlers instead of removing a document under a made-up key.
 #[derive(Clone)]
-pub struct PrimaryKeyFn(Arc<dyn Fn(&PreUpdateOldValueAccessor) -> String + Send + Sync>);
+pub struct PrimaryKeyFn(Arc<dyn Fn(&dyn RowAccessor) -> Option<String> + Send + Sync>);
 
 impl PrimaryKeyFn {
     pub fn new<F>(f: F) -> Self
     where
-        F: Fn(&PreUpdateOldValueAccessor) -> String + Send + Sync + 'static,
+        F: Fn(&dyn RowAccessor) -> Option<String> + Send + Sync + 'static,
     {
         Self(Arc::new(f))
     }
 }
 
+/// Maps a table to the milli index its rows are stored in.
+///
+/// `update_query` selects the document(s) for a single row and has the row's `rowid` bound as
+/// its only parameter. `rebuild_query` selects the documents for every row in the table and is
+/// used when the index is rebuilt from scratch.
+///
+/// `batch_update_query` is an optional version of `update_query` that selects the documents
+/// for many rows at once. It has a JSON array of rowids bound as its only parameter, e.g.
+/// `select ... from artist where rowid in (select value from json_each(?))`. When it's set,
+/// rows written in the same batch are fetched together instead of one query per row.
+///
+/// With [`UpdateMethod::Update`], the queried fields are merged into the existing documents
+/// instead of replacing them, so several tables can each fill in their own fields of a shared
+/// document. Such a table doesn't own the document, so deleting one of its rows, changing its
+/// key or the row no longer matching `filter` leaves the document and its fields in place. The
+/// table that inserts the documents with [`UpdateMethod::Replace`] is responsible for deleting
+/// them.
+///
+/// For composite or prefixed document ids, use a [`DocumentKey`] to generate both the key
+/// column of the queries and `primary_key_fn` so they always agree.
+///
+/// `filter` is an optional SQL predicate over the table's columns, e.g. `deleted_at is null`,
+/// that decides whether a row is indexed at all. When an inserted or updated row doesn't match
+/// it, the documents returned for the row by `update_query` are deleted from the index
+/// instead, so `update_query` should not apply the filter itself. `rebuild_query` on the other
+/// hand should only select matching rows.
 #[derive(Derivative, Clone)]
 #[derivative(Debug)]
 pub struct TableIndexSettings {
     pub index_name: String,
     pub update_query: String,
+    pub batch_update_query: Option<String>,
+    pub rebuild_query: String,
+    pub update_method: UpdateMethod,
+    pub filter: Option<String>,
     #[derivative(Debug = "ignore")]
     pub primary_key_fn: PrimaryKeyFn,
 }
 
+/// Builds a document id from one or more columns, with an optional prefix, so several tables
+/// can share one index without their ids colliding, e.g. `song-12` and `album-12`.
+///
+/// The same key is produced on the SQL side by [`select_expression`](Self::select_expression)
+/// and on the hook side by [`primary_key_fn`](Self::primary_key_fn), so deletes and upserts
+/// always agree on the document id. Null values are treated as empty strings on both sides.
+/// Real values may be formatted differently by SQLite and Rust, so avoid them in keys.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DocumentKey {
+    /// Name of the document field that holds the key.
+    pub field: String,
+    pub prefix: String,
+    pub columns: Vec<String>,
+    /// Placed between the column values when there's more than one column.
+    pub separator: String,
+}
+
+impl DocumentKey {
+    /// A key that's just the value of a single column.
+    pub fn column(column: impl Into<String>) -> Self {
+        let column = column.into();
+        Self {
+            field: column.clone(),
+            columns: vec![column],
+            ..Default::default()
+        }
+    }
+
+    /// Returns a SQL select expression, e.g. `'song-' || "song_id" as "entry_id"`, to use
+    /// in the update and rebuild queries.
+    pub fn select_expression(&self) -> String {
+        self.qualified_select_expression(None)
+    }
+
+    /// Same as [`select_expression`](Self::select_expression), with the columns qualified by
+    /// the table name to avoid ambiguity in joins.
+    pub(crate) fn qualified_select_expression(&self, table: Option<&str>) -> String {
+        let quote_column = |column: &str| match table {
+            Some(table) => format!("{}.{}", quote_identifier(table), quote_identifier(column)),
+            None => quote_identifier(column),
+        };
+        let expression = match self.columns.as_slice() {
+            // coalesce returns the column's value with its own type, so integer keys stay
+            // integers and only nulls are replaced
+            [column] if self.prefix.is_empty() => {
+                format!("coalesce({}, '')", quote_column(column))
+            }
+            columns => {
+                let mut parts = Vec::new();
+                if !self.prefix.is_empty() {
+                    parts.push(quote_literal(&self.prefix));
+                }
+                for (i, column) in columns.iter().enumerate() {
+                    if i > 0 && !self.separator.is_empty() {
+                        parts.push(quote_literal(&self.separator));
+                    }
+                    parts.push(format!("coalesce({}, '')", quote_column(column)));
+                }
+                parts.join(" || ")
+            }
+        };
+        format!("{expression} as {}", quote_identifier(&self.field))
+    }
+
+    /// Builds the key from the values of the key columns, in the same order as `columns`.
+    pub fn format_key<'a>(&self, values: impl IntoIterator<Item = ValueRef<'a>>) -> String {
+        let values: Vec<_> = values.into_iter().map(value_to_key).collect();
+        format!("{}{}", self.prefix, values.join(&self.separator))
+    }
+
+    /// Returns a key function that reads the key columns from the given positions in the
+    /// table, in the same order as `columns`.
+    pub fn primary_key_fn(&self, column_positions: Vec<i32>) -> PrimaryKeyFn {
+        self.resolved_primary_key_fn(KeyPositions {
+            positions: column_positions
+                .into_iter()
+                .map(|position| Arc::new(AtomicI32::new(position)))
+                .collect(),
+            schema: Default::default(),
+        })
+    }
+
+    pub(crate) fn resolved_primary_key_fn(&self, key_positions: KeyPositions) -> PrimaryKeyFn {
+        let key = self.clone();
+        PrimaryKeyFn::new(move |accessor| {
+            // The positions may point to the wrong columns once the schema has changed
+            if !key_positions.schema.is_resolved() {
+                return None;
+            }
+            let positions: Vec<_> = key_positions
+                .positions
+                .iter()
+                .map(|position| position.load(Ordering::SeqCst))
+                .collect();
+            // Not resolved yet, which can only happen if resolving the schema failed
+            if positions.iter().any(|position| *position < 0) {
+                return None;
+            }
+            Some(
+                key.format_key(
+                    positions
+                        .into_iter()
+                        .map(|position| accessor.column_value(position)),
+                ),
+            )
+        })
+    }
+}
+
+/// Counts the schema changes seen by the hooks, so column positions can be checked against the
+/// schema they were resolved for.
+#[derive(Debug, Default)]
+pub(crate) struct SchemaGeneration {
+    current: AtomicU64,
+    resolved: AtomicU64,
+}
+
+impl SchemaGeneration {
+    /// Marks the resolved positions as outdated until they're resolved again.
+    pub(crate) fn invalidate(&self) {
+        self.current.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn current(&self) -> u64 {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    /// Records that the positions were resolved after the given generation was read.
+    pub(crate) fn mark_resolved(&self, generation: u64) {
+        self.resolved.store(generation, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_resolved(&self) -> bool {
+        self.resolved.load(Ordering::SeqCst) == self.current()
+    }
+}
+
+/// Positions of the key columns in the table, kept up to date by the updater.
+#[derive(Clone, Debug)]
+pub(crate) struct KeyPositions {
+    pub(crate) positions: Vec<Arc<AtomicI32>>,
+    pub(crate) schema: Arc<SchemaGeneration>,
+}
+
+/// Maps the columns of a table to an index without writing the queries and key function by
+/// hand.
+///
+/// Column positions are looked up by name with `PRAGMA table_info` when the mapping is
+/// registered and again whenever the schema changes, so the mapping keeps working when columns
+/// are added or reordered. The document id is built by `document_key`, which is usually just
+/// the table's primary key column, e.g. `DocumentKey::column("artist_id")`. The key field is
+/// always included in the documents and is expected to be the index's primary key.
+///
+/// Deletes are keyed with the column positions that were resolved before the last schema
+/// change. Until the updater has resolved them again, deletes are skipped and reported as
+/// [`SkaldError::UnresolvedKey`] rather than risk removing the wrong document. Call
+/// [`refresh_schema`](pool::SqliteConnectionHandler::refresh_schema) after a migration to
+/// resolve them right away.
+#[derive(Clone, Debug, Default)]
+pub struct TableMapping {
+    pub table: String,
+    pub index_name: String,
+    pub document_key: DocumentKey,
+    /// Columns to include in each document, named after the column.
+    pub columns: Vec<String>,
+    pub update_method: UpdateMethod,
+    /// Only rows matching this SQL predicate are indexed. See [`TableIndexSettings`].
+    pub filter: Option<String>,
+}
+
+impl TableMapping {
+    fn select_query(&self, database: &str) -> String {
+        let key = &self.document_key;
+        let mut columns = vec![key.select_expression()];
+        columns.extend(
+            self.columns
+                .iter()
+                .filter(|column| **column != key.field)
+                .map(|column| quote_identifier(column)),
+        );
+        format!(
+            "select {} from {}.{}",
+            columns.join(", "),
+            quote_identifier(database),
+            quote_identifier(&self.table)
+        )
+    }
+
+    /// Generates the settings for the mapping. The key function reads the key columns from
+    /// whichever positions `key_positions` currently point to.
+    pub(crate) fn index_settings(
+        &self,
+        database: &str,
+        key_positions: KeyPositions,
+    ) -> TableIndexSettings {
+        let select_query = self.select_query(database);
+        TableIndexSettings {
+            index_name: self.index_name.clone(),
+            update_query: format!("{select_query} where rowid = ?"),
+            batch_update_query: Some(format!("{select_query} where rowid in {BATCH_ROWIDS}")),
+            rebuild_query: match &self.filter {
+                Some(filter) => format!("{select_query} where ({filter})"),
+                None => select_query,
+            },
+            update_method: self.update_method,
+            filter: self.filter.clone(),
+            primary_key_fn: self.document_key.resolved_primary_key_fn(key_positions),
+        }
+    }
+}
+
+/// Declares that documents in another index are built from this table's rows, e.g. a song
+/// document that embeds its artist's name.
+///
+/// When a row in the dependency table is inserted or updated, `dependent_query` is run with
+/// the changed row's `rowid` bound as the only parameter, and every document it returns is
+/// re-upserted into `index_name`. Deletions are not propagated; those are expected to be
+/// handled by the dependent table's own [`TableIndexSettings`].
+///
+/// The dependent table's [`filter`](TableIndexSettings::filter) isn't applied to these
+/// documents, so `dependent_query` needs to include it. Otherwise a change to the dependency
+/// would add documents the filter is meant to leave out.
+#[derive(Debug, Clone)]
+pub struct TableDependency {
+    pub index_name: String,
+    pub dependent_query: String,
+    pub update_method: UpdateMethod,
+}
+
 #[derive(Debug)]
 pub enum TableUpdate {
-    Delete { primary_key: String },
-    Upsert { rowid: i64, update_query: String },
+    Delete {
+        primary_key: String,
+    },
+    Upsert {
+        rowid: i64,
+        update_query: String,
+        batch_update_query: Option<String>,
+        update_method: UpdateMethod,
+        /// Selects the rowids that still match the table's filter, with a JSON array of
+        /// rowids bound
+        filter_query: Option<String>,
+    },
 }
 
 pub trait StatementExt {
-    fn query_to_json<P: Params>(&mut self, params: P) -> Vec<Document>;
+    fn query_to_json<P: Params>(&mut self, params: P) -> rusqlite::Result<Vec<Document>>;
 }
 
 impl StatementExt for Statement<'_> {
-    fn query_to_json<P: Params>(&mut self, params: P) -> Vec<Document> {
-        self.query_map(params, |row| {
-            let json_iter = (0..row.as_ref().column_count()).map(|col| {
-                let column_name = row.as_ref().column_name(col).unwrap().to_owned();
-                let sqlite_value_ref = row.get_ref_unwrap(col);
-
-                let json_value = match sqlite_value_ref {
-                    ValueRef::Text(sqlite_value) => {
-                        if !sqlite_value.starts_with(&[b'"'])
-                            && !sqlite_value.starts_with(&[b'{'])
-                            && !sqlite_value.starts_with(&[b'['])
-                        {
-                            serde_json::Value::from(std::str::from_utf8(sqlite_value).unwrap())
-                        } else {
-                            serde_json::Value::column_result(sqlite_value_ref)
-                                .or_else(|_| {
-                                    Ok::<_, Infallible>(serde_json::Value::from(
-                                        std::str::from_utf8(sqlite_value).unwrap(),
-                                    ))
-                                })
-                                .unwrap()
-                        }
+    fn query_to_json<P: Params>(&mut self, params: P) -> rusqlite::Result<Vec<Document>> {
+        self.query_map(params, row_to_json)?.collect()
+    }
+}
+
+pub(crate) fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// Expands the JSON array of rowids bound to a batch query.
+pub(crate) const BATCH_ROWIDS: &str = "(select value from json_each(?))";
+
+/// Returns a query that selects which of the bound rowids match the filter.
+pub(crate) fn filter_query(database: &str, table: &str, filter: &str) -> String {
+    format!(
+        "select rowid from {}.{} where rowid in {BATCH_ROWIDS} and ({filter})",
+        quote_identifier(database),
+        quote_identifier(table)
+    )
+}
+
+pub(crate) fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Converts a column value to a document key the same way it's converted when the row is
+/// turned into a document.
+pub(crate) fn value_to_key(value: ValueRef<'_>) -> String {
+    match value {
+        ValueRef::Null => String::new(),
+        ValueRef::Integer(value) => value.to_string(),
+        ValueRef::Real(value) => value.to_string(),
+        ValueRef::Text(value) | ValueRef::Blob(value) => {
+            String::from_utf8_lossy(value).into_owned()
+        }
+    }
+}
+
+pub(crate) fn row_to_json(row: &Row) -> rusqlite::Result<Document> {
+    (0..row.as_ref().column_count())
+        .map(|col| {
+            let column_name = row.as_ref().column_name(col)?.to_owned();
+            let sqlite_value_ref = row.get_ref(col)?;
+
+            let json_value = match sqlite_value_ref {
+                ValueRef::Text(sqlite_value) => {
+                    let text =
+                        std::str::from_utf8(sqlite_value).map_err(rusqlite::Error::Utf8Error)?;
+                    if !sqlite_value.starts_with(&[b'"'])
+                        && !sqlite_value.starts_with(&[b'{'])
+                        && !sqlite_value.starts_with(&[b'['])
+                    {
+                        serde_json::Value::from(text)
+                    } else {
+                        serde_json::Value::column_result(sqlite_value_ref)
+                            .unwrap_or_else(|_| serde_json::Value::from(text))
                     }
-                    _ => serde_json::Value::column_result(sqlite_value_ref).unwrap(),
-                };
+                }
+                _ => serde_json::Value::column_result(sqlite_value_ref).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        col,
+                        sqlite_value_ref.data_type(),
+                        Box::new(e),
+                    )
+                })?,
+            };
 
-                (column_name, json_value)
-            });
-            Ok(serde_json::map::Map::from_iter(json_iter))
+            Ok((column_name, json_value))
         })
-        .unwrap()
-        .map(|r| r.unwrap())
         .collect()
-    }
 }
 
 trait DashMapExt<K, V> {
@@ -99,3 +439,95 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use serde_json::json;
+
+    use super::*;
+
+    fn sql_keys(connection: &Connection, key: &DocumentKey) -> Vec<serde_json::Value> {
+        connection
+            .prepare(&format!(
+                "select {} from t order by rowid",
+                key.select_expression()
+            ))
+            .unwrap()
+            .query_to_json([])
+            .unwrap()
+            .into_iter()
+            .map(|mut document| document.remove(&key.field).unwrap())
+            .collect()
+    }
+
+    fn hook_keys(connection: &Connection, key: &DocumentKey) -> Vec<String> {
+        let mut statement = connection
+            .prepare("select a, b from t order by rowid")
+            .unwrap();
+        let mut rows = statement.query([]).unwrap();
+        let mut keys = Vec::new();
+        while let Some(row) = rows.next().unwrap() {
+            let values = key
+                .columns
+                .iter()
+                .map(|column| row.get_ref(column.as_str()).unwrap());
+            keys.push(key.format_key(values));
+        }
+        keys
+    }
+
+    #[test]
+    fn select_expression_matches_format_key() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "create table t(a, b);
+                insert into t values (1, 'x'), (null, 'y'), (2, null), ('text', 3);",
+            )
+            .unwrap();
+
+        let keys = [
+            DocumentKey::column("a"),
+            DocumentKey {
+                prefix: "p-".to_owned(),
+                ..DocumentKey::column("a")
+            },
+            DocumentKey {
+                field: "key".to_owned(),
+                columns: vec!["a".to_owned(), "b".to_owned()],
+                separator: "-".to_owned(),
+                ..Default::default()
+            },
+            DocumentKey {
+                field: "key".to_owned(),
+                prefix: "song-".to_owned(),
+                columns: vec!["a".to_owned(), "b".to_owned()],
+                separator: "/".to_owned(),
+            },
+        ];
+        for key in keys {
+            let sql_keys: Vec<_> = sql_keys(&connection, &key)
+                .into_iter()
+                .map(|value| match value {
+                    serde_json::Value::String(value) => value,
+                    serde_json::Value::Number(value) => value.to_string(),
+                    value => panic!("unexpected key {value}"),
+                })
+                .collect();
+            assert_eq!(hook_keys(&connection, &key), sql_keys, "{key:?}");
+        }
+    }
+
+    #[test]
+    fn single_column_keys_keep_their_type() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch("create table t(a, b); insert into t values (1, null), (null, null);")
+            .unwrap();
+        assert_eq!(
+            vec![json!(1), json!("")],
+            sql_keys(&connection, &DocumentKey::column("a"))
+        );
+    }
+}
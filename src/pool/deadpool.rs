@@ -3,9 +3,12 @@ use deadpool::{managed, Runtime};
 use deadpool_sqlite::{Config, ConfigError, Metrics};
 use deadpool_sync::SyncWrapper;
 
-use crate::{embedded_milli::Instance, TableIndexSettings};
+use crate::{
+    embedded_milli::Instance, SkaldError, TableDependency, TableIndexSettings, TableMapping,
+    UnifiedIndex,
+};
 
-use super::SqliteConnectionHandler;
+use super::{SqliteConnectionHandler, SyncConfig};
 
 deadpool::managed_reexports!(
     "skald",
@@ -22,13 +25,18 @@ pub struct Manager {
 
 impl Manager {
     #[must_use]
-    pub fn from_config(config: &Config, runtime: Runtime, instance: Instance) -> Self {
+    pub fn from_config(
+        config: &Config,
+        runtime: Runtime,
+        instance: Instance,
+        sync_config: SyncConfig,
+    ) -> Self {
         let path = config.path.clone();
         let conn = rusqlite::Connection::open(path).unwrap();
         let inner = deadpool_sqlite::Manager::from_config(config, runtime);
 
         Self {
-            handler: SqliteConnectionHandler::new(conn, instance),
+            handler: SqliteConnectionHandler::new(conn, instance, sync_config),
             inner,
         }
     }
@@ -44,6 +52,62 @@ impl Manager {
             inner: self.inner,
         }
     }
+
+    pub fn with_dependency(
+        self,
+        database: String,
+        table: String,
+        dependencies: Vec<TableDependency>,
+    ) -> Self {
+        Self {
+            handler: self.handler.with_dependency(database, table, dependencies),
+            inner: self.inner,
+        }
+    }
+
+    pub fn with_mapping(self, database: String, mapping: TableMapping) -> Result<Self, SkaldError> {
+        Ok(Self {
+            handler: self.handler.with_mapping(database, mapping)?,
+            inner: self.inner,
+        })
+    }
+
+    pub fn with_unified_index(
+        self,
+        database: String,
+        unified_index: UnifiedIndex,
+    ) -> Result<Self, SkaldError> {
+        Ok(Self {
+            handler: self.handler.with_unified_index(database, unified_index)?,
+            inner: self.inner,
+        })
+    }
+
+    pub fn with_error_handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(&SkaldError) + Send + Sync + 'static,
+    {
+        Self {
+            handler: self.handler.with_error_handler(handler),
+            inner: self.inner,
+        }
+    }
+
+    pub async fn flush(&self) {
+        self.handler.flush_async().await;
+    }
+
+    pub async fn rebuild(&self, index_name: impl Into<String>) -> Result<(), SkaldError> {
+        self.handler.rebuild_async(index_name).await
+    }
+
+    pub fn dirty_indexes(&self) -> Vec<String> {
+        self.handler.dirty_indexes()
+    }
+
+    pub fn shutdown(&self) {
+        self.handler.shutdown();
+    }
 }
 
 #[async_trait]
@@ -1,13 +1,29 @@
-use crate::{embedded_milli::Instance, DashMapExt, StatementExt, TableIndexSettings, TableUpdate};
-use crossbeam::{channel, select};
-use dashmap::DashMap;
-use parking_lot::RwLock;
-use rusqlite::{hooks::Action, preupdate_hook::PreUpdateCase, Connection};
+use crate::{
+    embedded_milli::{Instance, UpdateMethod},
+    DashMapExt, DocumentKey, KeyPositions, SchemaGeneration, SkaldError, TableDependency,
+    TableIndexSettings, TableMapping, TableUpdate, UnifiedIndex,
+};
+use crossbeam::channel::{self, TrySendError};
+use dashmap::{DashMap, DashSet};
+use derivative::Derivative;
+use futures_channel::oneshot;
+use parking_lot::{Mutex, RwLock};
+use rusqlite::{
+    hooks::{Action, AuthAction, AuthContext, Authorization},
+    preupdate_hook::PreUpdateCase,
+    Connection,
+};
 use std::{
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc,
+    },
     thread::{self, JoinHandle},
     time::Duration,
 };
+use updater::{
+    report_error, ErrorHandlers, IndexUpdater, MappedColumn, RegisteredSettings, UpdaterMessage,
+};
 
 #[cfg(feature = "deadpool")]
 pub mod deadpool;
@@ -15,22 +31,120 @@ pub mod deadpool;
 pub mod r2d2;
 #[cfg(feature = "sqlx")]
 pub mod sqlx;
+mod updater;
+
+/// Controls how the index updater batches committed changes before applying them to milli.
+///
+/// A batch is applied once no new commits have arrived for `debounce`, or earlier if it has
+/// been collecting commits for longer than `max_batch_age` or any index has more than
+/// `max_pending_updates` updates queued. Even then, the updater waits until `debounce` has
+/// passed since the last commit it received, since that commit may still be finishing.
+///
+/// Committed changes are sent to the updater over a channel that's unbounded by default. Setting
+/// `channel_capacity` bounds it, and `backpressure_policy` decides what happens to a commit
+/// while the channel is full.
+///
+/// `update_chunk_size` is the maximum number of rows fetched at a time by a batch update query.
+/// `rebuild_chunk_size` is the number of rows that are indexed at a time during a
+/// [`rebuild`](SqliteConnectionHandler::rebuild). If `rebuild_outdated_indexes` is set, indexes
+/// that were written by an older milli version are rebuilt automatically once the first
+/// connection has its hooks attached. Indexes that the current milli version can't open at all
+/// are recreated empty with their previous settings before being rebuilt; without this option,
+/// opening them returns an error instead.
+#[derive(Clone, Debug, Derivative)]
+#[derivative(Default)]
+pub struct SyncConfig {
+    #[derivative(Default(value = "Duration::from_millis(20)"))]
+    pub debounce: Duration,
+    #[derivative(Default(value = "Duration::from_secs(1)"))]
+    pub max_batch_age: Duration,
+    #[derivative(Default(value = "10_000"))]
+    pub max_pending_updates: usize,
+    pub channel_capacity: Option<usize>,
+    pub backpressure_policy: BackpressurePolicy,
+    #[derivative(Default(value = "500"))]
+    pub update_chunk_size: usize,
+    #[derivative(Default(value = "1_000"))]
+    pub rebuild_chunk_size: usize,
+    pub rebuild_outdated_indexes: bool,
+}
+
+/// Determines how a commit is handled when the bounded update channel is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Block the committing connection until the updater has caught up. Note that this also
+    /// blocks the async executor when used with an async pool.
+    #[default]
+    Block,
+    /// Drop the commit's updates and mark the affected indexes as dirty. Dirty indexes are
+    /// reported by [`SqliteConnectionHandler::dirty_indexes`] and need a full rebuild.
+    MarkDirty,
+    /// Fail the commit hook, which rolls the transaction back.
+    Rollback,
+}
 
 pub struct SqliteConnectionHandler {
-    table_settings: Arc<DashMap<(String, String), Vec<TableIndexSettings>>>,
-    update_tx: channel::Sender<DashMap<String, Vec<TableUpdate>>>,
-    _updater_handle: JoinHandle<()>,
+    instance: Instance,
+    table_settings: Arc<DashMap<(String, String), Vec<RegisteredSettings>>>,
+    table_dependencies: Arc<DashMap<(String, String), Vec<TableDependency>>>,
+    // Names of all tables that have settings or dependencies registered, used to skip writes
+    // to other tables without allocating a lookup key
+    registered_tables: Arc<DashSet<String>>,
+    // Columns of registered table mappings whose positions are kept up to date by the updater
+    mapped_columns: Arc<RwLock<Vec<MappedColumn>>>,
+    schema_generation: Arc<SchemaGeneration>,
+    error_handlers: ErrorHandlers,
+    dirty_indexes: Arc<DashSet<String>>,
+    backpressure_policy: BackpressurePolicy,
+    // Set once the check for outdated indexes has been queued so it only runs once
+    rebuild_outdated_indexes: AtomicBool,
+    update_tx: channel::Sender<UpdaterMessage>,
+    updater_handle: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl SqliteConnectionHandler {
-    pub fn new(conn: Connection, instance: Instance) -> Self {
-        let (update_tx, update_rx) = channel::unbounded();
+    pub fn new(conn: Connection, instance: Instance, config: SyncConfig) -> Self {
+        let mut instance = instance;
+        // Indexes that can't be opened by the current milli version can only be recreated when
+        // something rebuilds them afterwards
+        instance.recreate_incompatible_indexes = config.rebuild_outdated_indexes;
+        let (update_tx, update_rx) = match config.channel_capacity {
+            Some(capacity) => channel::bounded(capacity),
+            None => channel::unbounded(),
+        };
+        let backpressure_policy = config.backpressure_policy;
+        let rebuild_outdated_indexes = config.rebuild_outdated_indexes;
+        let table_settings = Arc::<DashMap<_, _>>::default();
+        let dirty_indexes = Arc::<DashSet<_>>::default();
+        let mapped_columns = Arc::<RwLock<_>>::default();
+        let schema_generation = Arc::<SchemaGeneration>::default();
+        let error_handlers = ErrorHandlers::default();
 
-        let handle = thread::spawn(move || index_updater(instance, update_rx, conn));
+        let updater = IndexUpdater {
+            instance: instance.clone(),
+            connection: conn,
+            config,
+            table_settings: table_settings.clone(),
+            dirty_indexes: dirty_indexes.clone(),
+            mapped_columns: mapped_columns.clone(),
+            schema_generation: schema_generation.clone(),
+            error_handlers: error_handlers.clone(),
+            schema_version: Default::default(),
+        };
+        let handle = thread::spawn(move || updater.run(update_rx));
         Self {
-            table_settings: Default::default(),
+            instance,
+            table_settings,
+            table_dependencies: Default::default(),
+            registered_tables: Default::default(),
+            mapped_columns,
+            schema_generation,
+            error_handlers,
+            dirty_indexes,
+            backpressure_policy,
+            rebuild_outdated_indexes: AtomicBool::new(rebuild_outdated_indexes),
             update_tx,
-            _updater_handle: handle,
+            updater_handle: Mutex::new(Some(handle)),
         }
     }
 
@@ -40,51 +154,429 @@ impl SqliteConnectionHandler {
         table: String,
         settings: Vec<TableIndexSettings>,
     ) -> Self {
-        {
-            let mut index_updates = self.table_settings.get_or_insert_entry((database, table));
-            index_updates.get_mut().extend(settings);
-        }
+        self.register_table(database, table, settings);
+        self
+    }
 
+    pub fn with_dependency(
+        self,
+        database: String,
+        table: String,
+        dependencies: Vec<TableDependency>,
+    ) -> Self {
+        self.register_dependency(database, table, dependencies);
         self
     }
 
+    /// Registers a table using a [`TableMapping`] instead of hand-written settings.
+    ///
+    /// Blocks until the column positions have been resolved, and fails if any of the mapped
+    /// columns don't exist.
+    pub fn with_mapping(self, database: String, mapping: TableMapping) -> Result<Self, SkaldError> {
+        self.register_mapping(database, mapping)?;
+        Ok(self)
+    }
+
+    /// Registers every source of a [`UnifiedIndex`] and applies the settings it needs to the
+    /// index.
+    pub fn with_unified_index(
+        self,
+        database: String,
+        unified_index: UnifiedIndex,
+    ) -> Result<Self, SkaldError> {
+        self.register_unified_index(database, unified_index)?;
+        Ok(self)
+    }
+
+    /// Registers a callback that's invoked whenever keeping the indexes in sync fails.
+    ///
+    /// Failed updates are skipped and the updater keeps processing later commits, so this is
+    /// the only place these errors surface.
+    pub fn with_error_handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(&SkaldError) + Send + Sync + 'static,
+    {
+        self.register_error_handler(handler);
+        self
+    }
+
+    pub(crate) fn register_table(
+        &self,
+        database: String,
+        table: String,
+        settings: Vec<TableIndexSettings>,
+    ) {
+        self.registered_tables.insert(table.clone());
+        let settings: Vec<_> = settings
+            .into_iter()
+            .map(|settings| RegisteredSettings::new(&database, &table, settings))
+            .collect();
+        let mut index_updates = self.table_settings.get_or_insert_entry((database, table));
+        index_updates.get_mut().extend(settings);
+    }
+
+    pub(crate) fn register_dependency(
+        &self,
+        database: String,
+        table: String,
+        dependencies: Vec<TableDependency>,
+    ) {
+        self.registered_tables.insert(table.clone());
+        let mut table_dependencies = self
+            .table_dependencies
+            .get_or_insert_entry((database, table));
+        table_dependencies.get_mut().extend(dependencies);
+    }
+
+    pub(crate) fn register_mapping(
+        &self,
+        database: String,
+        mapping: TableMapping,
+    ) -> Result<(), SkaldError> {
+        let key_positions = self.register_columns(
+            &database,
+            &mapping.table,
+            &mapping.document_key,
+            &mapping.columns,
+        );
+        let settings = mapping.index_settings(&database, key_positions);
+        self.register_table(database, mapping.table, vec![settings]);
+        self.refresh_schema()
+    }
+
+    pub(crate) fn register_unified_index(
+        &self,
+        database: String,
+        unified_index: UnifiedIndex,
+    ) -> Result<(), SkaldError> {
+        if let Some(entry_type) = unified_index.duplicate_entry_type() {
+            return Err(SkaldError::DuplicateEntryType {
+                index_name: unified_index.index_name.clone(),
+                entry_type: entry_type.to_owned(),
+            });
+        }
+        if let Some((prefix, other_prefix)) = unified_index.overlapping_key_prefixes() {
+            return Err(SkaldError::OverlappingKeyPrefixes {
+                index_name: unified_index.index_name.clone(),
+                prefix,
+                other_prefix,
+            });
+        }
+        if let Some(entry_type) = unified_index.source_without_key() {
+            return Err(SkaldError::EmptyKeyColumns {
+                index_name: unified_index.index_name.clone(),
+                entry_type: entry_type.to_owned(),
+            });
+        }
+
+        let index_name = &unified_index.index_name;
+        let index =
+            self.instance
+                .get_index(index_name)
+                .map_err(|source| SkaldError::OpenIndex {
+                    index_name: index_name.clone(),
+                    source,
+                })?;
+        let indexing_error = |source| SkaldError::Indexing {
+            index_name: index_name.clone(),
+            keys: Vec::new(),
+            source,
+        };
+        let mut wtxn = index.try_write().map_err(indexing_error)?;
+        let filterable_fields = index
+            .get_settings(&wtxn)
+            .map_err(indexing_error)?
+            .filterable_fields;
+        index
+            .update_settings(&mut wtxn, unified_index.settings_patch(filterable_fields))
+            .map_err(indexing_error)?;
+        index.commit(wtxn).map_err(indexing_error)?;
+
+        for source in &unified_index.sources {
+            let key_positions = self.register_columns(
+                &database,
+                &source.table,
+                &unified_index.document_key(source),
+                &[],
+            );
+            let settings = unified_index.index_settings(&database, source, key_positions);
+            self.register_table(database.clone(), source.table.clone(), vec![settings]);
+        }
+        self.refresh_schema()
+    }
+
+    /// Adds the columns to the ones the updater resolves, returning where the positions of the
+    /// key columns will be stored.
+    fn register_columns(
+        &self,
+        database: &str,
+        table: &str,
+        key: &DocumentKey,
+        columns: &[String],
+    ) -> KeyPositions {
+        let key_positions: Vec<_> = key
+            .columns
+            .iter()
+            .map(|_| Arc::new(AtomicI32::new(-1)))
+            .collect();
+        // Only the key columns' positions are needed; the other columns are selected by name
+        // but still checked so that typos are caught up front
+        let key_columns = key
+            .columns
+            .iter()
+            .cloned()
+            .zip(key_positions.iter().cloned().map(Some));
+        let other_columns = columns.iter().cloned().map(|column| (column, None));
+        self.mapped_columns
+            .write()
+            .extend(
+                key_columns
+                    .chain(other_columns)
+                    .map(|(column, position)| MappedColumn {
+                        database: database.to_owned(),
+                        table: table.to_owned(),
+                        column,
+                        position,
+                    }),
+            );
+        KeyPositions {
+            positions: key_positions,
+            schema: self.schema_generation.clone(),
+        }
+    }
+
+    pub(crate) fn register_error_handler<F>(&self, handler: F)
+    where
+        F: Fn(&SkaldError) + Send + Sync + 'static,
+    {
+        self.error_handlers.write().push(Arc::new(handler));
+    }
+
+    /// Returns the indexes that missed updates, either because the update channel was full when
+    /// using [`BackpressurePolicy::MarkDirty`] or because applying the updates failed.
+    pub fn dirty_indexes(&self) -> Vec<String> {
+        self.dirty_indexes
+            .iter()
+            .map(|name| name.key().clone())
+            .collect()
+    }
+
+    /// Blocks until every update committed before this call is visible in the indexes.
+    pub fn flush(&self) {
+        let (done_tx, done_rx) = channel::bounded(1);
+        let notify = Box::new(move || {
+            let _ = done_tx.send(());
+        });
+        // If the updater has already shut down, there's nothing left to wait for
+        if self.update_tx.send(UpdaterMessage::Flush(notify)).is_ok() {
+            let _ = done_rx.recv();
+        }
+    }
+
+    /// Async version of [`flush`](Self::flush).
+    pub async fn flush_async(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        let notify = Box::new(move || {
+            let _ = done_tx.send(());
+        });
+        if self.update_tx.send(UpdaterMessage::Flush(notify)).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    /// Rebuilds the index from scratch using the `rebuild_query` of every table that feeds it,
+    /// blocking until it's done.
+    ///
+    /// Updates committed while the rebuild is running are applied afterwards, so the index
+    /// ends up consistent with the database. Readers see the old documents until the rebuild
+    /// has finished.
+    pub fn rebuild(&self, index_name: impl Into<String>) -> Result<(), SkaldError> {
+        let (done_tx, done_rx) = channel::bounded(1);
+        let done = Box::new(move |result| {
+            let _ = done_tx.send(result);
+        });
+        self.update_tx
+            .send(UpdaterMessage::Rebuild {
+                index_name: index_name.into(),
+                done,
+            })
+            .map_err(|_| SkaldError::Shutdown)?;
+        done_rx.recv().map_err(|_| SkaldError::Shutdown)?
+    }
+
+    /// Async version of [`rebuild`](Self::rebuild).
+    pub async fn rebuild_async(&self, index_name: impl Into<String>) -> Result<(), SkaldError> {
+        let (done_tx, done_rx) = oneshot::channel();
+        let done = Box::new(move |result| {
+            let _ = done_tx.send(result);
+        });
+        self.update_tx
+            .send(UpdaterMessage::Rebuild {
+                index_name: index_name.into(),
+                done,
+            })
+            .map_err(|_| SkaldError::Shutdown)?;
+        done_rx.await.map_err(|_| SkaldError::Shutdown)?
+    }
+
+    /// Looks up the column positions of every registered [`TableMapping`] again.
+    ///
+    /// Schema changes made through connections with hooks attached are picked up by the
+    /// updater shortly after they're committed. Deletes made before that can't be keyed and
+    /// are reported as [`SkaldError::UnresolvedKey`]. Call this right after migrating the
+    /// schema to avoid that.
+    pub fn refresh_schema(&self) -> Result<(), SkaldError> {
+        let (done_tx, done_rx) = channel::bounded(1);
+        let done = Box::new(move |result| {
+            let _ = done_tx.send(result);
+        });
+        self.update_tx
+            .send(UpdaterMessage::RefreshSchema { done })
+            .map_err(|_| SkaldError::Shutdown)?;
+        done_rx.recv().map_err(|_| SkaldError::Shutdown)?
+    }
+
+    /// Applies every update that has been committed so far to the indexes and stops the
+    /// index updater thread. Commits made after shutting down are no longer indexed.
+    ///
+    /// This is called automatically when the handler is dropped.
+    pub fn shutdown(&self) {
+        let Some(handle) = self.updater_handle.lock().take() else {
+            return;
+        };
+        // The updater only stops listening once it has exited, so this can't fail unless the
+        // thread panicked
+        let _ = self.update_tx.send(UpdaterMessage::Shutdown);
+        let _ = handle.join();
+    }
+
+    /// Attaches the hooks that track changes to the registered tables. This also sets the
+    /// connection's authorizer, which is used to detect schema changes, so it replaces any
+    /// authorizer that was set before.
     pub fn attach_hooks(&self, connection: &Connection) {
+        // Tables are registered before any connections are created, so this is the first
+        // point where we know which indexes are fed by the database
+        if self.rebuild_outdated_indexes.swap(false, Ordering::SeqCst) {
+            let _ = self.update_tx.send(UpdaterMessage::RebuildOutdated);
+        }
+
+        // Set when the connection prepares a statement that changes the schema, so the column
+        // positions can be marked outdated before any rows are written with the new layout
+        let schema_changed = Arc::new(AtomicBool::new(false));
+        let schema_changed_ = schema_changed.clone();
+        let schema_generation = self.schema_generation.clone();
+        connection.authorizer(Some(move |context: AuthContext<'_>| {
+            if matches!(
+                context.action,
+                AuthAction::CreateTable { .. }
+                    | AuthAction::DropTable { .. }
+                    | AuthAction::AlterTable { .. }
+            ) {
+                schema_generation.invalidate();
+                schema_changed_.store(true, Ordering::SeqCst);
+            }
+            Authorization::Allow
+        }));
+
         let table_settings = self.table_settings.clone();
+        let registered_tables = self.registered_tables.clone();
         let pending_updates = Arc::new(RwLock::new(DashMap::<_, Vec<TableUpdate>>::new()));
         let pending_updates_ = pending_updates.clone();
+        let error_handlers = self.error_handlers.clone();
         connection.preupdate_hook(Some(
             move |_action, db_name: &str, table_name: &str, preupdate_case: &_| {
-                if let PreUpdateCase::Delete(accessor) = preupdate_case {
-                    let index_settings = table_settings
-                        .get(&(db_name.to_owned(), table_name.to_owned()))
-                        .unwrap();
-                    for settings in index_settings.iter() {
-                        let primary_key = (settings.primary_key_fn.0)(accessor);
-                        let pending_updates_read = pending_updates_.read();
-                        let mut entry =
-                            pending_updates_read.get_or_insert_entry(settings.index_name.clone());
+                if !matches!(
+                    preupdate_case,
+                    PreUpdateCase::Delete(_) | PreUpdateCase::Update { .. }
+                ) || !registered_tables.contains(table_name)
+                {
+                    return;
+                }
+                // Tables that are only registered as a dependency don't need their deletes tracked
+                let Some(index_settings) =
+                    table_settings.get(&(db_name.to_owned(), table_name.to_owned()))
+                else {
+                    return;
+                };
 
-                        entry.get_mut().push(TableUpdate::Delete { primary_key });
-                    }
+                let pending_updates_read = pending_updates_.read();
+                // Tables that only merge fields into another table's documents don't delete
+                // them, see `TableIndexSettings`
+                for settings in index_settings
+                    .iter()
+                    .filter(|settings| settings.update_method == UpdateMethod::Replace)
+                {
+                    let primary_key = match preupdate_case {
+                        PreUpdateCase::Delete(accessor) => (settings.primary_key_fn.0)(accessor),
+                        PreUpdateCase::Update {
+                            old_value_accessor,
+                            new_value_accessor,
+                        } => {
+                            let old_key = (settings.primary_key_fn.0)(old_value_accessor);
+                            let new_key = (settings.primary_key_fn.0)(new_value_accessor);
+                            // The update hook will upsert the document under its new key,
+                            // so the one stored under the old key needs to be removed
+                            if old_key.is_some() && old_key == new_key {
+                                continue;
+                            }
+                            old_key
+                        }
+                        _ => return,
+                    };
+                    let Some(primary_key) = primary_key else {
+                        report_error(
+                            &error_handlers,
+                            &SkaldError::UnresolvedKey {
+                                index_name: settings.index_name.clone(),
+                                database: db_name.to_owned(),
+                                table: table_name.to_owned(),
+                            },
+                        );
+                        continue;
+                    };
+                    let mut entry =
+                        pending_updates_read.get_or_insert_entry(settings.index_name.clone());
+                    entry.get_mut().push(TableUpdate::Delete { primary_key });
                 }
             },
         ));
 
         let table_settings = self.table_settings.clone();
+        let table_dependencies = self.table_dependencies.clone();
+        let registered_tables = self.registered_tables.clone();
         let pending_updates_ = pending_updates.clone();
         connection.update_hook(Some(
             move |action, db_name: &str, table_name: &str, rowid| {
                 if let Action::SQLITE_INSERT | Action::SQLITE_UPDATE = action {
-                    let index_settings = table_settings
-                        .get(&(db_name.to_owned(), table_name.to_owned()))
-                        .unwrap();
-                    for settings in index_settings.iter() {
-                        let pending_updates_read = pending_updates_.read();
+                    if !registered_tables.contains(table_name) {
+                        return;
+                    }
+                    let key = (db_name.to_owned(), table_name.to_owned());
+                    let index_settings = table_settings.get(&key);
+                    let dependencies = table_dependencies.get(&key);
+
+                    let pending_updates_read = pending_updates_.read();
+                    for settings in index_settings.iter().flat_map(|s| s.iter()) {
                         let mut entry =
                             pending_updates_read.get_or_insert_entry(settings.index_name.clone());
                         entry.get_mut().push(TableUpdate::Upsert {
                             rowid,
                             update_query: settings.update_query.clone(),
+                            batch_update_query: settings.batch_update_query.clone(),
+                            update_method: settings.update_method,
+                            filter_query: settings.filter_query.clone(),
+                        });
+                    }
+                    // Documents in other indexes that were built from this row are stale now too
+                    for dependency in dependencies.iter().flat_map(|d| d.iter()) {
+                        let mut entry =
+                            pending_updates_read.get_or_insert_entry(dependency.index_name.clone());
+                        entry.get_mut().push(TableUpdate::Upsert {
+                            rowid,
+                            update_query: dependency.dependent_query.clone(),
+                            batch_update_query: None,
+                            update_method: dependency.update_method,
+                            filter_query: None,
                         });
                     }
                 }
@@ -93,78 +585,59 @@ impl SqliteConnectionHandler {
 
         let pending_updates_ = pending_updates.clone();
         let update_tx = self.update_tx.clone();
+        let dirty_indexes = self.dirty_indexes.clone();
+        let backpressure_policy = self.backpressure_policy;
+        let schema_changed_ = schema_changed.clone();
+        let schema_generation = self.schema_generation.clone();
         connection.commit_hook(Some(move || {
+            // The updater may have resolved the positions between preparing the statement and
+            // committing it, so they need to be marked outdated again
+            if schema_changed_.swap(false, Ordering::SeqCst) {
+                schema_generation.invalidate();
+                let _ = update_tx.try_send(UpdaterMessage::SchemaChanged);
+            }
             let old = std::mem::take(&mut *pending_updates_.write());
-            update_tx.send(old).unwrap();
-            false
+            if old.is_empty() {
+                return false;
+            }
+            // Sending only fails with a disconnected error once the handler has been shut down,
+            // at which point updates are intentionally discarded
+            match backpressure_policy {
+                BackpressurePolicy::Block => {
+                    let _ = update_tx.send(UpdaterMessage::Updates(old));
+                    false
+                }
+                BackpressurePolicy::MarkDirty => {
+                    if let Err(TrySendError::Full(UpdaterMessage::Updates(updates))) =
+                        update_tx.try_send(UpdaterMessage::Updates(old))
+                    {
+                        for index_name in updates.into_iter().map(|(index_name, _)| index_name) {
+                            dirty_indexes.insert(index_name);
+                        }
+                    }
+                    false
+                }
+                // Returning true from the commit hook turns the commit into a rollback
+                BackpressurePolicy::Rollback => matches!(
+                    update_tx.try_send(UpdaterMessage::Updates(old)),
+                    Err(TrySendError::Full(_))
+                ),
+            }
         }));
 
+        let update_tx = self.update_tx.clone();
         connection.rollback_hook(Some(move || {
             pending_updates.read().clear();
+            // The positions were marked outdated when the statement was prepared
+            if schema_changed.swap(false, Ordering::SeqCst) {
+                let _ = update_tx.try_send(UpdaterMessage::SchemaChanged);
+            }
         }));
     }
 }
 
-fn index_updater(
-    instance: Instance,
-    update_rx: channel::Receiver<DashMap<String, Vec<TableUpdate>>>,
-    connection: Connection,
-) {
-    loop {
-        let updates = update_rx.recv().unwrap();
-
-        loop {
-            select! {
-                recv(update_rx) -> msg => {
-                    let msg = msg.unwrap();
-                    for (key, val) in msg.into_iter() {
-                        let mut index_updates = updates.get_or_insert_entry(key);
-                        index_updates.get_mut().extend(val);
-                    }
-
-                }
-                default(Duration::from_millis(20)) => {
-                    break;
-                }
-            }
-        }
-
-        for (index_name, updates) in updates.into_iter() {
-            let index = instance.get_index(index_name).unwrap();
-            let mut wtxn = index.write();
-
-            let upserts = updates.iter().filter_map(|u| {
-                if let TableUpdate::Upsert {
-                    rowid,
-                    update_query,
-                } = u
-                {
-                    Some((rowid, update_query))
-                } else {
-                    None
-                }
-            });
-
-            for (rowid, update_query) in upserts {
-                let mut statement = connection.prepare_cached(update_query).unwrap();
-                let docs = statement.query_to_json([rowid]);
-
-                index.add_documents(&mut wtxn, docs).unwrap();
-            }
-
-            let keys_to_delete: Vec<_> = updates
-                .iter()
-                .filter_map(|u| {
-                    if let TableUpdate::Delete { primary_key } = u {
-                        Some(primary_key.to_owned())
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-
-            index.delete_documents(&mut wtxn, keys_to_delete).unwrap();
-            wtxn.commit().unwrap();
-        }
+impl Drop for SqliteConnectionHandler {
+    fn drop(&mut self) {
+        self.shutdown();
     }
 }
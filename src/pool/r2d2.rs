@@ -1,19 +1,28 @@
 use r2d2::ManageConnection;
 use r2d2_sqlite::SqliteConnectionManager;
 
-use crate::{embedded_milli::Instance, TableIndexSettings};
+use std::sync::Arc;
 
-use super::SqliteConnectionHandler;
+use crate::{
+    embedded_milli::Instance, SkaldError, TableDependency, TableIndexSettings, TableMapping,
+    UnifiedIndex,
+};
+
+use super::{SqliteConnectionHandler, SyncConfig};
 
 pub struct SkaldConnectionManager {
     inner: SqliteConnectionManager,
-    handler: SqliteConnectionHandler,
+    handler: Arc<SqliteConnectionHandler>,
 }
 
 impl SkaldConnectionManager {
-    pub fn new(inner: SqliteConnectionManager, instance: Instance) -> Self {
+    pub fn new(inner: SqliteConnectionManager, instance: Instance, config: SyncConfig) -> Self {
         Self {
-            handler: SqliteConnectionHandler::new(inner.connect().unwrap(), instance),
+            handler: Arc::new(SqliteConnectionHandler::new(
+                inner.connect().unwrap(),
+                instance,
+                config,
+            )),
             inner,
         }
     }
@@ -24,10 +33,64 @@ impl SkaldConnectionManager {
         table: String,
         settings: Vec<TableIndexSettings>,
     ) -> Self {
-        Self {
-            handler: self.handler.with_table(database, table, settings),
-            inner: self.inner,
-        }
+        self.handler.register_table(database, table, settings);
+        self
+    }
+
+    pub fn with_dependency(
+        self,
+        database: String,
+        table: String,
+        dependencies: Vec<TableDependency>,
+    ) -> Self {
+        self.handler
+            .register_dependency(database, table, dependencies);
+        self
+    }
+
+    pub fn with_mapping(self, database: String, mapping: TableMapping) -> Result<Self, SkaldError> {
+        self.handler.register_mapping(database, mapping)?;
+        Ok(self)
+    }
+
+    pub fn with_unified_index(
+        self,
+        database: String,
+        unified_index: UnifiedIndex,
+    ) -> Result<Self, SkaldError> {
+        self.handler
+            .register_unified_index(database, unified_index)?;
+        Ok(self)
+    }
+
+    pub fn with_error_handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(&SkaldError) + Send + Sync + 'static,
+    {
+        self.handler.register_error_handler(handler);
+        self
+    }
+
+    /// Returns the underlying handler. Since the manager is moved into the pool, this can be
+    /// used to keep access to [`flush`](SqliteConnectionHandler::flush) afterwards.
+    pub fn handler(&self) -> Arc<SqliteConnectionHandler> {
+        self.handler.clone()
+    }
+
+    pub fn flush(&self) {
+        self.handler.flush();
+    }
+
+    pub fn rebuild(&self, index_name: impl Into<String>) -> Result<(), SkaldError> {
+        self.handler.rebuild(index_name)
+    }
+
+    pub fn dirty_indexes(&self) -> Vec<String> {
+        self.handler.dirty_indexes()
+    }
+
+    pub fn shutdown(&self) {
+        self.handler.shutdown();
     }
 }
 
@@ -1,7 +1,7 @@
-use super::SqliteConnectionHandler;
+use super::{SqliteConnectionHandler, SyncConfig};
 use crate::{
     embedded_milli::{Document, Instance},
-    TableIndexSettings,
+    SkaldError, TableDependency, TableIndexSettings, TableMapping, UnifiedIndex,
 };
 use async_trait::async_trait;
 use futures_core::future::BoxFuture;
@@ -13,7 +13,7 @@ use sqlx::{
 use std::sync::{Arc, Mutex};
 
 pub struct SkaldHooks {
-    handler: SqliteConnectionHandler,
+    handler: Arc<SqliteConnectionHandler>,
 }
 
 #[async_trait]
@@ -111,9 +111,13 @@ impl SkaldHooks {
     pub async fn new(
         connection: impl IntoConnection<rusqlite::Connection>,
         instance: Instance,
+        config: SyncConfig,
     ) -> Self {
-        let handler = SqliteConnectionHandler::new(connection.into_connection().await, instance);
-        Self { handler }
+        let handler =
+            SqliteConnectionHandler::new(connection.into_connection().await, instance, config);
+        Self {
+            handler: Arc::new(handler),
+        }
     }
 
     pub fn with_table(
@@ -122,16 +126,65 @@ impl SkaldHooks {
         table: String,
         settings: Vec<TableIndexSettings>,
     ) -> Self {
-        Self {
-            handler: self.handler.with_table(database, table, settings),
-        }
+        self.handler.register_table(database, table, settings);
+        self
     }
 
-    pub fn build(
+    pub fn with_dependency(
+        self,
+        database: String,
+        table: String,
+        dependencies: Vec<TableDependency>,
+    ) -> Self {
+        self.handler
+            .register_dependency(database, table, dependencies);
+        self
+    }
+
+    pub fn with_mapping(self, database: String, mapping: TableMapping) -> Result<Self, SkaldError> {
+        self.handler.register_mapping(database, mapping)?;
+        Ok(self)
+    }
+
+    pub fn with_unified_index(
         self,
+        database: String,
+        unified_index: UnifiedIndex,
+    ) -> Result<Self, SkaldError> {
+        self.handler
+            .register_unified_index(database, unified_index)?;
+        Ok(self)
+    }
+
+    pub fn with_error_handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(&SkaldError) + Send + Sync + 'static,
+    {
+        self.handler.register_error_handler(handler);
+        self
+    }
+
+    pub async fn flush(&self) {
+        self.handler.flush_async().await;
+    }
+
+    pub async fn rebuild(&self, index_name: impl Into<String>) -> Result<(), SkaldError> {
+        self.handler.rebuild_async(index_name).await
+    }
+
+    pub fn dirty_indexes(&self) -> Vec<String> {
+        self.handler.dirty_indexes()
+    }
+
+    pub fn shutdown(&self) {
+        self.handler.shutdown();
+    }
+
+    pub fn build(
+        &self,
     ) -> impl Fn(&mut SqliteConnection, PoolConnectionMetadata) -> BoxFuture<'_, Result<(), sqlx::Error>>
     {
-        let handler = Arc::new(self.handler);
+        let handler = self.handler.clone();
         let conns = Arc::new(Mutex::new(vec![]));
 
         move |conn: &mut SqliteConnection, _: PoolConnectionMetadata| {
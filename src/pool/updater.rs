@@ -0,0 +1,1003 @@
+use crate::{
+    embedded_milli::{Document, Instance, UpdateMethod},
+    filter_query, row_to_json, DashMapExt, SchemaGeneration, SkaldError, StatementExt,
+    TableIndexSettings, TableUpdate,
+};
+use crossbeam::{channel, select};
+use dashmap::{DashMap, DashSet};
+use parking_lot::RwLock;
+use rusqlite::{Connection, OptionalExtension};
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+use super::SyncConfig;
+
+pub(super) type ErrorHandlers = Arc<RwLock<Vec<Arc<dyn Fn(&SkaldError) + Send + Sync>>>>;
+
+pub(super) enum UpdaterMessage {
+    Updates(DashMap<String, Vec<TableUpdate>>),
+    Flush(Box<dyn FnOnce() + Send>),
+    Rebuild {
+        index_name: String,
+        done: Box<dyn FnOnce(Result<(), SkaldError>) + Send>,
+    },
+    RebuildOutdated,
+    /// Sent by the hooks after a schema change was committed or rolled back.
+    SchemaChanged,
+    RefreshSchema {
+        done: Box<dyn FnOnce(Result<(), SkaldError>) + Send>,
+    },
+    Shutdown,
+}
+
+/// A column of a [`TableMapping`](crate::TableMapping) that needs to exist, along with where
+/// to store its position if the key function needs it.
+pub(super) struct MappedColumn {
+    pub(super) database: String,
+    pub(super) table: String,
+    pub(super) column: String,
+    pub(super) position: Option<Arc<AtomicI32>>,
+}
+
+/// [`TableIndexSettings`] along with the queries that are derived from them, so the hooks
+/// don't need to build them on every write.
+pub(super) struct RegisteredSettings {
+    settings: TableIndexSettings,
+    pub(super) filter_query: Option<String>,
+}
+
+impl RegisteredSettings {
+    pub(super) fn new(database: &str, table: &str, settings: TableIndexSettings) -> Self {
+        Self {
+            filter_query: settings
+                .filter
+                .as_ref()
+                .map(|filter| filter_query(database, table, filter)),
+            settings,
+        }
+    }
+}
+
+impl Deref for RegisteredSettings {
+    type Target = TableIndexSettings;
+
+    fn deref(&self) -> &Self::Target {
+        &self.settings
+    }
+}
+
+/// Applies committed changes to the milli indexes. Runs on its own thread and owns a
+/// dedicated connection for running the update queries.
+pub(super) struct IndexUpdater {
+    pub(super) instance: Instance,
+    pub(super) connection: Connection,
+    pub(super) config: SyncConfig,
+    pub(super) table_settings: Arc<DashMap<(String, String), Vec<RegisteredSettings>>>,
+    pub(super) dirty_indexes: Arc<DashSet<String>>,
+    pub(super) mapped_columns: Arc<RwLock<Vec<MappedColumn>>>,
+    pub(super) schema_generation: Arc<SchemaGeneration>,
+    pub(super) error_handlers: ErrorHandlers,
+    // Schema version the mapped column positions were resolved against
+    pub(super) schema_version: Cell<Option<i64>>,
+}
+
+impl IndexUpdater {
+    pub(super) fn run(self, update_rx: channel::Receiver<UpdaterMessage>) {
+        let mut next_message = None;
+        loop {
+            let message = match next_message.take() {
+                Some(message) => message,
+                None => match update_rx.recv() {
+                    Ok(message) => message,
+                    Err(_) => return,
+                },
+            };
+
+            match message {
+                UpdaterMessage::Updates(updates) => {
+                    next_message = self.collect_batch(&update_rx, &updates);
+                    if let Err(e) = self.refresh_schema_if_changed() {
+                        report_error(&self.error_handlers, &e);
+                    }
+                    self.apply_updates(updates);
+                }
+                UpdaterMessage::Flush(notify) => notify(),
+                UpdaterMessage::Rebuild { index_name, done } => done(self.rebuild(&index_name)),
+                UpdaterMessage::RebuildOutdated => self.rebuild_outdated(),
+                UpdaterMessage::SchemaChanged => {
+                    // Like updates, this is sent before the commit has finished
+                    thread::sleep(self.config.debounce);
+                    if let Err(e) = self.resolve_mapped_columns() {
+                        report_error(&self.error_handlers, &e);
+                    }
+                }
+                UpdaterMessage::RefreshSchema { done } => done(self.resolve_mapped_columns()),
+                UpdaterMessage::Shutdown => return,
+            }
+        }
+    }
+
+    /// Merges incoming updates into the batch until it's ready to be applied. Any other message
+    /// ends the batch early and is returned so it can be handled once the batch is applied.
+    fn collect_batch(
+        &self,
+        update_rx: &channel::Receiver<UpdaterMessage>,
+        updates: &DashMap<String, Vec<TableUpdate>>,
+    ) -> Option<UpdaterMessage> {
+        let batch_start = Instant::now();
+        let mut last_received = batch_start;
+        loop {
+            // Keep bulk writes from building up an unbounded batch that only gets indexed once
+            // the writes stop
+            let batch_age = batch_start.elapsed();
+            if batch_age >= self.config.max_batch_age
+                || updates
+                    .iter()
+                    .any(|entry| entry.value().len() >= self.config.max_pending_updates)
+            {
+                // The commit hook runs before SQLite has finished committing, so the last
+                // commit may not be visible to our connection yet. Give it the same grace
+                // period as a regular batch and leave newer commits for the next batch.
+                thread::sleep(self.config.debounce.saturating_sub(last_received.elapsed()));
+                return None;
+            }
+            let timeout = self
+                .config
+                .debounce
+                .min(self.config.max_batch_age - batch_age);
+
+            select! {
+                recv(update_rx) -> msg => {
+                    match msg {
+                        Ok(UpdaterMessage::Updates(msg)) => {
+                            last_received = Instant::now();
+                            for (key, val) in msg.into_iter() {
+                                let mut index_updates = updates.get_or_insert_entry(key);
+                                index_updates.get_mut().extend(val);
+                            }
+                        }
+                        // Flushes, rebuilds and shutdowns all need to see the current batch
+                        // applied, so don't wait for the debounce period
+                        Ok(msg) => return Some(msg),
+                        Err(_) => return Some(UpdaterMessage::Shutdown),
+                    }
+                }
+                default(timeout) => {
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn refresh_schema_if_changed(&self) -> Result<(), SkaldError> {
+        if self.mapped_columns.read().is_empty()
+            || (self.schema_generation.is_resolved()
+                && self.schema_version.get() == Some(self.read_schema_version()?))
+        {
+            return Ok(());
+        }
+        self.resolve_mapped_columns()
+    }
+
+    fn read_schema_version(&self) -> Result<i64, SkaldError> {
+        self.connection
+            .query_row("PRAGMA schema_version", [], |row| row.get(0))
+            .map_err(|source| SkaldError::SchemaQuery { source })
+    }
+
+    /// Looks up the positions of the mapped columns by name.
+    fn resolve_mapped_columns(&self) -> Result<(), SkaldError> {
+        // Read before looking up the positions, so a schema change that happens in the
+        // meantime still leaves them marked outdated
+        let generation = self.schema_generation.current();
+        let schema_version = self.read_schema_version()?;
+        for mapped_column in self.mapped_columns.read().iter() {
+            let position: Option<i32> = self
+                .connection
+                .prepare_cached("select cid from pragma_table_info(?1, ?2) where name = ?3")
+                .and_then(|mut statement| {
+                    statement
+                        .query_row(
+                            [
+                                &mapped_column.table,
+                                &mapped_column.database,
+                                &mapped_column.column,
+                            ],
+                            |row| row.get(0),
+                        )
+                        .optional()
+                })
+                .map_err(|source| SkaldError::SchemaQuery { source })?;
+            let position = position.ok_or_else(|| SkaldError::UnknownColumn {
+                database: mapped_column.database.clone(),
+                table: mapped_column.table.clone(),
+                column: mapped_column.column.clone(),
+            })?;
+            if let Some(stored_position) = &mapped_column.position {
+                stored_position.store(position, Ordering::SeqCst);
+            }
+        }
+        self.schema_version.set(Some(schema_version));
+        self.schema_generation.mark_resolved(generation);
+        Ok(())
+    }
+
+    fn apply_updates(&self, updates: DashMap<String, Vec<TableUpdate>>) {
+        // A failure only discards the updates for the affected index so the rest of the batch
+        // still gets applied. The index is out of sync after that, so it needs a rebuild.
+        for (index_name, updates) in updates.into_iter() {
+            if let Err(e) = self.apply_index_updates(&index_name, updates) {
+                self.dirty_indexes.insert(index_name);
+                report_error(&self.error_handlers, &e);
+            }
+        }
+    }
+
+    fn apply_index_updates(
+        &self,
+        index_name: &str,
+        updates: Vec<TableUpdate>,
+    ) -> Result<(), SkaldError> {
+        let index =
+            self.instance
+                .get_index(index_name)
+                .map_err(|source| SkaldError::OpenIndex {
+                    index_name: index_name.to_owned(),
+                    source,
+                })?;
+        let setup_error = |source| SkaldError::Indexing {
+            index_name: index_name.to_owned(),
+            keys: Vec::new(),
+            source,
+        };
+        let mut wtxn = index.try_write().map_err(setup_error)?;
+        let primary_key_field = index.primary_key(&wtxn).map_err(setup_error)?;
+
+        // Collapse the updates into the final state of each document so the outcome
+        // doesn't depend on how inserts and deletes were interleaved within the batch.
+        // The update queries read the current state of the rows, so the documents they return
+        // are never older than any of the deletes and are applied on top of them.
+        let mut documents = HashMap::<String, DocumentState>::new();
+        let mut upsert_groups = Vec::<UpsertGroup>::new();
+        for update in updates {
+            match update {
+                TableUpdate::Delete { primary_key } => {
+                    documents.insert(primary_key, DocumentState::Deleted);
+                }
+                TableUpdate::Upsert {
+                    rowid,
+                    update_query,
+                    batch_update_query,
+                    update_method,
+                    filter_query,
+                } => {
+                    // Group the rows by query so they can be fetched together
+                    match upsert_groups
+                        .iter_mut()
+                        .find(|group| group.update_query == update_query)
+                    {
+                        Some(group) => group.rowids.push(rowid),
+                        None => upsert_groups.push(UpsertGroup {
+                            update_query,
+                            batch_update_query,
+                            update_method,
+                            filter_query,
+                            rowids: vec![rowid],
+                        }),
+                    }
+                }
+            }
+        }
+        // Partial documents need to be merged into the replaced ones, not overwritten by them
+        upsert_groups.sort_by_key(|group| group.update_method == UpdateMethod::Update);
+
+        let mut unkeyed_documents = Vec::new();
+        for mut group in upsert_groups {
+            group.rowids.sort_unstable();
+            group.rowids.dedup();
+            // Everything collected so far is dropped along with the failed rows
+            let matching_rowids = self
+                .matching_rowids(index_name, &group)
+                .map_err(|e| e.with_keys(documents.keys().cloned()))?;
+            let (matching, not_matching): (Vec<_>, Vec<_>) = group
+                .rowids
+                .iter()
+                .copied()
+                .partition(|rowid| matching_rowids.contains(rowid));
+
+            // The update query still returns the documents of rows that no longer match the
+            // filter, which gives us the keys to delete. Without a key, there's no way to
+            // delete the document. Partial documents are only merged into documents owned by
+            // another table, so deleting them would remove that table's fields as well.
+            if group.update_method == UpdateMethod::Replace {
+                let not_matching_docs = self
+                    .fetch_documents(index_name, &group, &not_matching)
+                    .map_err(|e| e.with_keys(documents.keys().cloned()))?;
+                for doc in not_matching_docs {
+                    if let Some(key) = primary_key_field
+                        .as_deref()
+                        .and_then(|field| document_key(&doc, field))
+                    {
+                        documents.insert(key, DocumentState::Deleted);
+                    }
+                }
+            }
+            let matching_docs = self
+                .fetch_documents(index_name, &group, &matching)
+                .map_err(|e| e.with_keys(documents.keys().cloned()))?;
+            for doc in matching_docs {
+                match primary_key_field
+                    .as_deref()
+                    .and_then(|field| document_key(&doc, field))
+                {
+                    Some(key) => {
+                        let state = documents.remove(&key);
+                        documents
+                            .insert(key, DocumentState::apply(state, doc, group.update_method));
+                    }
+                    None => unkeyed_documents.push((doc, group.update_method)),
+                }
+            }
+        }
+
+        let keys: Vec<_> = documents.keys().cloned().collect();
+        let indexing_error = |source| SkaldError::Indexing {
+            index_name: index_name.to_owned(),
+            keys: keys.clone(),
+            source,
+        };
+
+        let mut keys_to_delete = Vec::new();
+        let mut replaced_docs = Vec::new();
+        let mut updated_docs = Vec::new();
+        let unkeyed_documents = unkeyed_documents.into_iter();
+        let keyed_documents = documents
+            .into_iter()
+            .filter_map(|(key, state)| match state {
+                DocumentState::Deleted => {
+                    keys_to_delete.push(key);
+                    None
+                }
+                DocumentState::Replace(doc) => Some((doc, UpdateMethod::Replace)),
+                DocumentState::Update(doc) => Some((doc, UpdateMethod::Update)),
+            });
+        for (doc, update_method) in unkeyed_documents.chain(keyed_documents) {
+            match update_method {
+                UpdateMethod::Replace => replaced_docs.push(doc),
+                UpdateMethod::Update => updated_docs.push(doc),
+            }
+        }
+
+        if !keys_to_delete.is_empty() {
+            index
+                .delete_documents(&mut wtxn, keys_to_delete)
+                .map_err(indexing_error)?;
+        }
+        if !replaced_docs.is_empty() {
+            index
+                .add_documents(&mut wtxn, replaced_docs)
+                .map_err(indexing_error)?;
+        }
+        if !updated_docs.is_empty() {
+            index
+                .update_documents(&mut wtxn, updated_docs)
+                .map_err(indexing_error)?;
+        }
+        wtxn.commit()
+            .map_err(|e| indexing_error(anyhow::Error::from(e)))?;
+
+        Ok(())
+    }
+
+    /// Returns the rows of the group that match its filter. Rows without a filter always match.
+    fn matching_rowids(
+        &self,
+        index_name: &str,
+        group: &UpsertGroup,
+    ) -> Result<HashSet<i64>, SkaldError> {
+        let Some(filter_query) = &group.filter_query else {
+            return Ok(group.rowids.iter().copied().collect());
+        };
+        let mut matching_rowids = HashSet::new();
+        for chunk in group.rowids.chunks(self.config.update_chunk_size.max(1)) {
+            let rowids = self
+                .connection
+                .prepare_cached(filter_query)
+                .and_then(|mut statement| {
+                    statement
+                        .query_map([rowids_to_json(chunk)], |row| row.get::<_, i64>(0))?
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                })
+                .map_err(|source| SkaldError::BatchUpdateQuery {
+                    index_name: index_name.to_owned(),
+                    rowids: chunk.to_vec(),
+                    keys: Vec::new(),
+                    source,
+                })?;
+            matching_rowids.extend(rowids);
+        }
+        Ok(matching_rowids)
+    }
+
+    /// Runs the group's update query for the rows, in chunks if it has a batch query.
+    fn fetch_documents(
+        &self,
+        index_name: &str,
+        group: &UpsertGroup,
+        rowids: &[i64],
+    ) -> Result<Vec<Document>, SkaldError> {
+        let mut documents = Vec::new();
+        match &group.batch_update_query {
+            Some(batch_update_query) => {
+                for chunk in rowids.chunks(self.config.update_chunk_size.max(1)) {
+                    let docs = self
+                        .connection
+                        .prepare_cached(batch_update_query)
+                        .and_then(|mut statement| statement.query_to_json([rowids_to_json(chunk)]))
+                        .map_err(|source| SkaldError::BatchUpdateQuery {
+                            index_name: index_name.to_owned(),
+                            rowids: chunk.to_vec(),
+                            keys: Vec::new(),
+                            source,
+                        })?;
+                    documents.extend(docs);
+                }
+            }
+            None => {
+                for &rowid in rowids {
+                    let docs = self
+                        .connection
+                        .prepare_cached(&group.update_query)
+                        .and_then(|mut statement| statement.query_to_json([rowid]))
+                        .map_err(|source| SkaldError::UpdateQuery {
+                            index_name: index_name.to_owned(),
+                            rowid,
+                            keys: Vec::new(),
+                            source,
+                        })?;
+                    documents.extend(docs);
+                }
+            }
+        }
+        Ok(documents)
+    }
+
+    /// Replaces the contents of the index with the results of the rebuild queries of every
+    /// table that feeds it.
+    ///
+    /// This runs on the updater thread, so commits that arrive in the meantime queue up and
+    /// are applied on top of the rebuilt index afterwards. Everything happens in a single
+    /// write transaction so readers keep seeing the old documents until it's done.
+    fn rebuild(&self, index_name: &str) -> Result<(), SkaldError> {
+        let mut rebuild_queries: Vec<_> = self
+            .table_settings
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .filter(|settings| settings.index_name == index_name)
+                    .map(|settings| (settings.rebuild_query.clone(), settings.update_method))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        // Tables that only fill in some fields need to be merged into the documents created by
+        // the other tables, not overwritten by them
+        rebuild_queries.sort_by_key(|(_, update_method)| *update_method == UpdateMethod::Update);
+        // Opening the index would create it, and clearing it would wipe out documents that
+        // nothing can restore, so bail out before touching anything
+        if rebuild_queries.is_empty() {
+            return Err(SkaldError::UnknownIndex {
+                index_name: index_name.to_owned(),
+            });
+        }
+
+        // Clear the dirty flag first so that any updates dropped while the rebuild is running
+        // mark the index as dirty again
+        self.dirty_indexes.remove(index_name);
+
+        let index =
+            self.instance
+                .get_index(index_name)
+                .map_err(|source| SkaldError::OpenIndex {
+                    index_name: index_name.to_owned(),
+                    source,
+                })?;
+        let indexing_error = |source| SkaldError::Indexing {
+            index_name: index_name.to_owned(),
+            keys: Vec::new(),
+            source,
+        };
+        let query_error = |source| SkaldError::RebuildQuery {
+            index_name: index_name.to_owned(),
+            source,
+        };
+
+        let mut wtxn = index.try_write().map_err(indexing_error)?;
+        index
+            .delete_all_documents(&mut wtxn)
+            .map_err(indexing_error)?;
+
+        for (rebuild_query, update_method) in rebuild_queries {
+            let mut statement = self
+                .connection
+                .prepare(&rebuild_query)
+                .map_err(query_error)?;
+            let mut rows = statement.query([]).map_err(query_error)?;
+
+            // Stream the rows into the index in chunks to avoid loading the whole table into
+            // memory at once
+            let mut chunk = Vec::with_capacity(self.config.rebuild_chunk_size);
+            while let Some(row) = rows.next().map_err(query_error)? {
+                chunk.push(row_to_json(row).map_err(query_error)?);
+                if chunk.len() >= self.config.rebuild_chunk_size {
+                    index
+                        .index_documents(&mut wtxn, std::mem::take(&mut chunk), update_method)
+                        .map_err(indexing_error)?;
+                }
+            }
+            if !chunk.is_empty() {
+                index
+                    .index_documents(&mut wtxn, chunk, update_method)
+                    .map_err(indexing_error)?;
+            }
+        }
+
+        wtxn.commit()
+            .map_err(|e| indexing_error(anyhow::Error::from(e)))?;
+        self.instance
+            .mark_up_to_date(index_name)
+            .map_err(indexing_error)?;
+
+        Ok(())
+    }
+
+    /// Rebuilds every registered index that was written by an older milli version.
+    fn rebuild_outdated(&self) {
+        let mut index_names: Vec<_> = self
+            .table_settings
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .map(|settings| settings.index_name.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        index_names.sort();
+        index_names.dedup();
+
+        for index_name in index_names {
+            let result = self
+                .instance
+                .needs_rebuilding(&index_name)
+                .map_err(|source| SkaldError::OpenIndex {
+                    index_name: index_name.clone(),
+                    source,
+                })
+                .and_then(|needs_rebuilding| {
+                    if needs_rebuilding {
+                        self.rebuild(&index_name)
+                    } else {
+                        Ok(())
+                    }
+                });
+            if let Err(e) = result {
+                report_error(&self.error_handlers, &e);
+            }
+        }
+    }
+}
+
+/// Upserts in a batch that share the same update query.
+struct UpsertGroup {
+    update_query: String,
+    batch_update_query: Option<String>,
+    update_method: UpdateMethod,
+    filter_query: Option<String>,
+    rowids: Vec<i64>,
+}
+
+/// The state of a document after all of the updates in a batch so far.
+enum DocumentState {
+    Deleted,
+    Replace(Document),
+    Update(Document),
+}
+
+impl DocumentState {
+    fn apply(state: Option<Self>, document: Document, update_method: UpdateMethod) -> Self {
+        match (state, update_method) {
+            (_, UpdateMethod::Replace) => Self::Replace(document),
+            (Some(Self::Replace(mut existing)), UpdateMethod::Update) => {
+                existing.extend(document);
+                Self::Replace(existing)
+            }
+            (Some(Self::Update(mut existing)), UpdateMethod::Update) => {
+                existing.extend(document);
+                Self::Update(existing)
+            }
+            // Nothing is left to merge into after a delete, so the partial document becomes
+            // the whole document
+            (Some(Self::Deleted), UpdateMethod::Update) => Self::Replace(document),
+            (None, UpdateMethod::Update) => Self::Update(document),
+        }
+    }
+}
+
+pub(super) fn report_error(error_handlers: &ErrorHandlers, error: &SkaldError) {
+    for handler in error_handlers.read().iter() {
+        handler(error);
+    }
+}
+
+/// Formats the rowids as a JSON array, to be expanded with `json_each` in batch queries.
+fn rowids_to_json(rowids: &[i64]) -> String {
+    serde_json::Value::from(rowids.to_vec()).to_string()
+}
+
+fn document_key(document: &Document, primary_key: &str) -> Option<String> {
+    match document.get(primary_key)? {
+        serde_json::Value::String(key) => Some(key.clone()),
+        serde_json::Value::Number(key) => Some(key.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        embedded_milli::{EmbeddedMilli, IndexSettings},
+        pool::{BackpressurePolicy, SqliteConnectionHandler},
+        DocumentKey, TableMapping,
+    };
+
+    fn document(value: serde_json::Value) -> Document {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn replace_after_delete() {
+        let state = DocumentState::apply(
+            Some(DocumentState::Deleted),
+            document(json!({ "id": 5, "name": "a" })),
+            UpdateMethod::Replace,
+        );
+        assert!(matches!(
+            state,
+            DocumentState::Replace(doc) if doc == document(json!({ "id": 5, "name": "a" }))
+        ));
+    }
+
+    #[test]
+    fn update_after_delete_becomes_replace() {
+        let state = DocumentState::apply(
+            Some(DocumentState::Deleted),
+            document(json!({ "id": 5, "name": "a" })),
+            UpdateMethod::Update,
+        );
+        assert!(matches!(
+            state,
+            DocumentState::Replace(doc) if doc == document(json!({ "id": 5, "name": "a" }))
+        ));
+    }
+
+    #[test]
+    fn update_merges_into_replace() {
+        let state = DocumentState::apply(
+            Some(DocumentState::Replace(document(
+                json!({ "id": 5, "name": "a", "genre": "rock" }),
+            ))),
+            document(json!({ "id": 5, "name": "b" })),
+            UpdateMethod::Update,
+        );
+        assert!(matches!(
+            state,
+            DocumentState::Replace(doc)
+                if doc == document(json!({ "id": 5, "name": "b", "genre": "rock" }))
+        ));
+    }
+
+    #[test]
+    fn update_merges_into_update() {
+        let state = DocumentState::apply(
+            Some(DocumentState::Update(document(
+                json!({ "id": 5, "name": "a" }),
+            ))),
+            document(json!({ "id": 5, "genre": "rock" })),
+            UpdateMethod::Update,
+        );
+        assert!(matches!(
+            state,
+            DocumentState::Update(doc)
+                if doc == document(json!({ "id": 5, "name": "a", "genre": "rock" }))
+        ));
+    }
+
+    #[test]
+    fn update_without_state_stays_partial() {
+        let state = DocumentState::apply(
+            None,
+            document(json!({ "id": 5, "name": "a" })),
+            UpdateMethod::Update,
+        );
+        assert!(matches!(state, DocumentState::Update(_)));
+    }
+
+    struct TestDb {
+        connection: Connection,
+        handler: SqliteConnectionHandler,
+        index: EmbeddedMilli,
+        _dir: TempDir,
+    }
+
+    impl TestDb {
+        fn new() -> Self {
+            Self::with_config(SyncConfig::default())
+        }
+
+        fn with_config(config: SyncConfig) -> Self {
+            Self::open(
+                config,
+                "create table artist(artist_id integer primary key, artist_name text not null)",
+                None,
+            )
+        }
+
+        /// Only indexes artists that haven't been soft deleted.
+        fn with_soft_delete() -> Self {
+            Self::open(
+                SyncConfig::default(),
+                "create table artist(
+                    artist_id integer primary key,
+                    artist_name text not null,
+                    deleted_at text
+                )",
+                Some("deleted_at is null"),
+            )
+        }
+
+        fn open(config: SyncConfig, schema: &str, filter: Option<&str>) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            // The updater needs its own connection, so the database can't be private to one
+            // in-memory connection
+            let db_uri = format!(
+                "file:{}?mode=memory&cache=shared",
+                dir.path().join("test.db").display()
+            );
+            let connection = Connection::open(&db_uri).unwrap();
+            connection.execute_batch(schema).unwrap();
+
+            let instance = Instance::new(dir.path());
+            let index = instance.get_index("artist").unwrap();
+            let mut wtxn = index.write();
+            index
+                .set_settings(
+                    &mut wtxn,
+                    IndexSettings {
+                        primary_key: Some("artist_id".to_owned()),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            index.commit(wtxn).unwrap();
+
+            let handler =
+                SqliteConnectionHandler::new(Connection::open(&db_uri).unwrap(), instance, config)
+                    .with_mapping(
+                        "main".to_owned(),
+                        TableMapping {
+                            table: "artist".to_owned(),
+                            index_name: "artist".to_owned(),
+                            document_key: DocumentKey::column("artist_id"),
+                            columns: vec!["artist_name".to_owned()],
+                            filter: filter.map(ToOwned::to_owned),
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+            handler.attach_hooks(&connection);
+
+            Self {
+                connection,
+                handler,
+                index,
+                _dir: dir,
+            }
+        }
+
+        fn execute(&self, sql: &str) {
+            self.connection.execute_batch(sql).unwrap();
+            self.handler.flush();
+        }
+
+        /// Keeps the updater busy until the returned sender is dropped, so the commits made in
+        /// the meantime stay in the channel.
+        fn block_updater(&self) -> channel::Sender<()> {
+            let (started_tx, started_rx) = channel::bounded(0);
+            let (release_tx, release_rx) = channel::bounded::<()>(0);
+            self.handler
+                .update_tx
+                .send(UpdaterMessage::Flush(Box::new(move || {
+                    started_tx.send(()).unwrap();
+                    let _ = release_rx.recv();
+                })))
+                .unwrap();
+            started_rx.recv().unwrap();
+            release_tx
+        }
+
+        fn artist_name(&self, artist_id: i64) -> Option<String> {
+            let rtxn = self.index.read();
+            self.index
+                .get_document(&rtxn, artist_id.to_string())
+                .unwrap()
+                .map(|document| document["artist_name"].as_str().unwrap().to_owned())
+        }
+    }
+
+    #[test]
+    fn delete_then_reinsert_keeps_document() {
+        let db = TestDb::new();
+        db.execute("insert into artist values (5, 'before')");
+        assert_eq!(Some("before".to_owned()), db.artist_name(5));
+
+        db.execute(
+            "begin;
+            delete from artist where artist_id = 5;
+            insert into artist values (5, 'after');
+            commit;",
+        );
+        assert_eq!(Some("after".to_owned()), db.artist_name(5));
+    }
+
+    #[test]
+    fn insert_then_delete_leaves_no_document() {
+        let db = TestDb::new();
+        db.execute(
+            "begin;
+            insert into artist values (7, 'gone');
+            delete from artist where artist_id = 7;
+            commit;",
+        );
+        assert_eq!(None, db.artist_name(7));
+    }
+
+    #[test]
+    fn primary_key_change_moves_document() {
+        let db = TestDb::new();
+        db.execute("insert into artist values (1, 'moved')");
+        assert_eq!(Some("moved".to_owned()), db.artist_name(1));
+
+        db.execute("update artist set artist_id = 2 where artist_id = 1");
+        assert_eq!(None, db.artist_name(1));
+        assert_eq!(Some("moved".to_owned()), db.artist_name(2));
+    }
+
+    #[test]
+    fn batches_over_the_update_limit_index_every_row() {
+        let db = TestDb::with_config(SyncConfig {
+            max_pending_updates: 10,
+            ..Default::default()
+        });
+        for artist_id in 0..100 {
+            db.connection
+                .execute(
+                    "insert into artist values (?1, ?2)",
+                    (artist_id, format!("artist {artist_id}")),
+                )
+                .unwrap();
+        }
+        db.handler.flush();
+
+        for artist_id in 0..100 {
+            assert_eq!(
+                Some(format!("artist {artist_id}")),
+                db.artist_name(artist_id)
+            );
+        }
+    }
+
+    #[test]
+    fn reordered_columns_never_delete_the_wrong_document() {
+        let db = TestDb::new();
+        // With outdated positions, the name of the first artist would be read as its key
+        db.execute("insert into artist values (1, '2'), (2, 'other')");
+
+        db.execute(
+            "begin;
+            create table artist_new(artist_name text not null, artist_id integer primary key);
+            insert into artist_new select artist_name, artist_id from artist;
+            drop table artist;
+            alter table artist_new rename to artist;
+            commit;
+            delete from artist where artist_id = 1;",
+        );
+        assert_eq!(Some("other".to_owned()), db.artist_name(2));
+
+        db.handler.refresh_schema().unwrap();
+        db.execute("delete from artist where artist_id = 2");
+        assert_eq!(None, db.artist_name(2));
+    }
+
+    #[test]
+    fn soft_deleted_rows_leave_the_index() {
+        let db = TestDb::with_soft_delete();
+        db.execute("insert into artist(artist_id, artist_name) values (1, 'hidden')");
+        assert_eq!(Some("hidden".to_owned()), db.artist_name(1));
+
+        db.execute("update artist set deleted_at = datetime('now') where artist_id = 1");
+        assert_eq!(None, db.artist_name(1));
+
+        db.execute("update artist set deleted_at = null where artist_id = 1");
+        assert_eq!(Some("hidden".to_owned()), db.artist_name(1));
+    }
+
+    #[test]
+    fn full_channel_marks_the_index_dirty() {
+        let db = TestDb::with_config(SyncConfig {
+            channel_capacity: Some(1),
+            backpressure_policy: BackpressurePolicy::MarkDirty,
+            ..Default::default()
+        });
+        let release = db.block_updater();
+        db.connection
+            .execute_batch("insert into artist values (1, 'queued')")
+            .unwrap();
+        db.connection
+            .execute_batch("insert into artist values (2, 'dropped')")
+            .unwrap();
+        assert_eq!(vec!["artist".to_owned()], db.handler.dirty_indexes());
+
+        drop(release);
+        db.handler.flush();
+        assert_eq!(Some("queued".to_owned()), db.artist_name(1));
+        assert_eq!(None, db.artist_name(2));
+    }
+
+    #[test]
+    fn full_channel_rolls_the_commit_back() {
+        let db = TestDb::with_config(SyncConfig {
+            channel_capacity: Some(1),
+            backpressure_policy: BackpressurePolicy::Rollback,
+            ..Default::default()
+        });
+        let release = db.block_updater();
+        db.connection
+            .execute_batch("insert into artist values (1, 'queued')")
+            .unwrap();
+        assert!(db
+            .connection
+            .execute_batch("insert into artist values (2, 'rolled back')")
+            .is_err());
+        let count: i64 = db
+            .connection
+            .query_row(
+                "select count(*) from artist where artist_id = 2",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(0, count);
+
+        drop(release);
+        db.handler.flush();
+        assert!(db.handler.dirty_indexes().is_empty());
+        assert_eq!(Some("queued".to_owned()), db.artist_name(1));
+        assert_eq!(None, db.artist_name(2));
+    }
+}
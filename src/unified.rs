@@ -0,0 +1,252 @@
+use crate::{
+    embedded_milli::{Setting, SettingsPatch, UpdateMethod},
+    quote_identifier, quote_literal, DocumentKey, KeyPositions, TableIndexSettings, BATCH_ROWIDS,
+};
+
+/// One table that feeds a [`UnifiedIndex`].
+///
+/// Documents are selected from the table with `projection` as the select list, after the
+/// generated key and entry type columns. `joins` is appended after the `from` clause to pull in
+/// columns from related tables. The source table can be referred to by its own name in both,
+/// e.g. `song.song_title as entry`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnifiedSource {
+    pub table: String,
+    /// Stored in each document's entry type field to tell the sources apart.
+    pub entry_type: String,
+    /// Prefix for the document ids of this source. Defaults to `{entry_type}-`. It can't be the
+    /// start of another source's prefix, or the sources could delete each other's documents.
+    pub key_prefix: Option<String>,
+    /// Columns of `table` that make up the document id. At least one is required.
+    pub key_columns: Vec<String>,
+    pub projection: String,
+    pub joins: String,
+    /// Only rows matching this SQL predicate are indexed. It's checked against the source
+    /// table alone, so it can't refer to joined tables. See [`TableIndexSettings`].
+    pub filter: Option<String>,
+}
+
+/// Several tables feeding one index, e.g. artists, albums and songs in a single search index.
+///
+/// Each source gets its own key prefix, so deletes from one table only ever remove that
+/// table's documents, and a rebuild of the index runs the queries of every source. When
+/// registered, the index's primary key is set to `key_field`, `entry_type_field` is made
+/// filterable, and `distinct_attribute` is applied if it's set.
+///
+/// Only changes to the source tables themselves are tracked. Changes to tables pulled in
+/// through `joins`, like the artist name on the albums below, aren't reindexed unless a
+/// [`TableDependency`](crate::TableDependency) is registered for them as well.
+///
+/// ```ignore
+/// UnifiedIndex::new("library")
+///     .with_source(UnifiedSource {
+///         table: "artist".to_owned(),
+///         entry_type: "artist".to_owned(),
+///         key_columns: vec!["artist_id".to_owned()],
+///         projection: "artist.artist_name as entry".to_owned(),
+///         ..Default::default()
+///     })
+///     .with_source(UnifiedSource {
+///         table: "album".to_owned(),
+///         entry_type: "album".to_owned(),
+///         key_columns: vec!["album_id".to_owned()],
+///         projection: "album.album_name as entry, artist.artist_name as artist".to_owned(),
+///         joins: "inner join artist on artist.artist_id = album.artist_id".to_owned(),
+///         ..Default::default()
+///     });
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnifiedIndex {
+    pub index_name: String,
+    pub key_field: String,
+    pub entry_type_field: String,
+    pub distinct_attribute: Option<String>,
+    pub sources: Vec<UnifiedSource>,
+}
+
+impl UnifiedIndex {
+    pub fn new(index_name: impl Into<String>) -> Self {
+        Self {
+            index_name: index_name.into(),
+            key_field: "entry_id".to_owned(),
+            entry_type_field: "entry_type".to_owned(),
+            distinct_attribute: None,
+            sources: Vec::new(),
+        }
+    }
+
+    pub fn with_source(mut self, source: UnifiedSource) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    pub fn with_distinct_attribute(mut self, distinct_attribute: impl Into<String>) -> Self {
+        self.distinct_attribute = Some(distinct_attribute.into());
+        self
+    }
+
+    /// Returns the first entry type that's used by more than one source. Entry types need to
+    /// be unique since the default key prefixes are derived from them.
+    pub(crate) fn duplicate_entry_type(&self) -> Option<&str> {
+        self.sources.iter().enumerate().find_map(|(i, source)| {
+            self.sources[..i]
+                .iter()
+                .any(|other| other.entry_type == source.entry_type)
+                .then_some(source.entry_type.as_str())
+        })
+    }
+
+    /// Returns the first pair of key prefixes where one starts with the other. The keys of
+    /// such sources can collide, e.g. `song-` with `live-1` and `song-live-` with `1`.
+    pub(crate) fn overlapping_key_prefixes(&self) -> Option<(String, String)> {
+        let prefixes: Vec<_> = self
+            .sources
+            .iter()
+            .map(|source| self.document_key(source).prefix)
+            .collect();
+        prefixes.iter().enumerate().find_map(|(i, prefix)| {
+            prefixes[..i]
+                .iter()
+                .find(|other| prefix.starts_with(other.as_str()) || other.starts_with(prefix))
+                .map(|other| (other.clone(), prefix.clone()))
+        })
+    }
+
+    /// Returns the entry type of the first source that has no key columns.
+    pub(crate) fn source_without_key(&self) -> Option<&str> {
+        self.sources
+            .iter()
+            .find(|source| source.key_columns.is_empty())
+            .map(|source| source.entry_type.as_str())
+    }
+
+    pub(crate) fn document_key(&self, source: &UnifiedSource) -> DocumentKey {
+        DocumentKey {
+            field: self.key_field.clone(),
+            prefix: source
+                .key_prefix
+                .clone()
+                .unwrap_or_else(|| format!("{}-", source.entry_type)),
+            columns: source.key_columns.clone(),
+            separator: "-".to_owned(),
+        }
+    }
+
+    /// Settings the index needs for the sources to work together. `filterable_fields` are the
+    /// index's current filterable fields, which are kept.
+    pub(crate) fn settings_patch(&self, mut filterable_fields: Vec<String>) -> SettingsPatch {
+        if !filterable_fields.contains(&self.entry_type_field) {
+            filterable_fields.push(self.entry_type_field.clone());
+        }
+        SettingsPatch {
+            primary_key: Setting::Set(self.key_field.clone()),
+            filterable_fields: Setting::Set(filterable_fields),
+            distinct_attribute: self
+                .distinct_attribute
+                .clone()
+                .map_or(Setting::NotSet, Setting::Set),
+            ..Default::default()
+        }
+    }
+
+    fn select_query(&self, database: &str, source: &UnifiedSource) -> String {
+        let mut columns = vec![
+            self.document_key(source)
+                .qualified_select_expression(Some(&source.table)),
+            format!(
+                "{} as {}",
+                quote_literal(&source.entry_type),
+                quote_identifier(&self.entry_type_field)
+            ),
+        ];
+        if !source.projection.is_empty() {
+            columns.push(source.projection.clone());
+        }
+        let mut query = format!(
+            "select {} from {}.{}",
+            columns.join(", "),
+            quote_identifier(database),
+            quote_identifier(&source.table)
+        );
+        if !source.joins.is_empty() {
+            query.push(' ');
+            query.push_str(&source.joins);
+        }
+        query
+    }
+
+    pub(crate) fn index_settings(
+        &self,
+        database: &str,
+        source: &UnifiedSource,
+        key_positions: KeyPositions,
+    ) -> TableIndexSettings {
+        let select_query = self.select_query(database, source);
+        TableIndexSettings {
+            index_name: self.index_name.clone(),
+            update_query: format!(
+                "{select_query} where {}.rowid = ?",
+                quote_identifier(&source.table)
+            ),
+            batch_update_query: Some(format!(
+                "{select_query} where {}.rowid in {BATCH_ROWIDS}",
+                quote_identifier(&source.table)
+            )),
+            rebuild_query: match &source.filter {
+                Some(filter) => format!("{select_query} where ({filter})"),
+                None => select_query,
+            },
+            update_method: UpdateMethod::Replace,
+            filter: source.filter.clone(),
+            primary_key_fn: self
+                .document_key(source)
+                .resolved_primary_key_fn(key_positions),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(entry_type: &str, key_prefix: Option<&str>) -> UnifiedSource {
+        UnifiedSource {
+            table: entry_type.to_owned(),
+            entry_type: entry_type.to_owned(),
+            key_prefix: key_prefix.map(ToOwned::to_owned),
+            key_columns: vec!["id".to_owned()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn distinct_prefixes_are_allowed() {
+        let index = UnifiedIndex::new("library")
+            .with_source(source("song", None))
+            .with_source(source("album", None))
+            .with_source(source("artist", Some("a:")));
+        assert_eq!(None, index.overlapping_key_prefixes());
+    }
+
+    #[test]
+    fn rejects_equal_prefixes() {
+        let index = UnifiedIndex::new("library")
+            .with_source(source("song", Some("x-")))
+            .with_source(source("album", Some("x-")));
+        assert_eq!(
+            Some(("x-".to_owned(), "x-".to_owned())),
+            index.overlapping_key_prefixes()
+        );
+    }
+
+    #[test]
+    fn rejects_nested_prefixes() {
+        let index = UnifiedIndex::new("library")
+            .with_source(source("song", None))
+            .with_source(source("live", Some("song-live-")));
+        assert_eq!(
+            Some(("song-".to_owned(), "song-live-".to_owned())),
+            index.overlapping_key_prefixes()
+        );
+    }
+}